@@ -1,20 +1,87 @@
-use std::ffi::OsStr;
+use std::collections::{HashMap, HashSet};
 use std::path;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
 
 use cpu_time::ProcessTime;
-use rebackup::{walker, WalkerConfig, WalkerErr, WalkerRule};
+use rebackup::{WalkerConfig, WalkerErr};
 
-use crate::config::BackupConfig;
+use crate::config::{BackupConfig, OutputFormat, SourceConfig, SyncMode};
 use std::fs::File;
 use std::io::Write;
 
+pub mod archive;
+pub mod atomic;
+pub mod crypto;
+pub mod delta;
+pub mod filters;
+pub mod ignore_files;
+pub mod journal;
+pub mod links;
+pub mod manifest;
+pub mod metadata;
+pub mod mirror;
+pub mod patterns;
+pub mod plan;
+pub mod priority;
+pub mod progress;
+pub mod repository;
+pub mod restore;
+pub mod retention;
+pub mod snapshot;
+pub mod source_snapshot;
+pub mod sources;
+pub mod space;
+pub mod verify;
+pub mod walk;
+
+use crypto::Cipher;
+use journal::Journal;
+use manifest::{manifest_path, Manifest, ManifestEntry};
+use priority::TierTracker;
+use progress::{BackupOutcome, ProgressEvent, ProgressState};
+use retention::PruneReport;
+use snapshot::Snapshot;
+use source_snapshot::SourceSnapshot;
+
 pub struct Backupper {
     backup_config: BackupConfig,
-    walker_config: WalkerConfig,
 
     added_size: Mutex<u64>,
     removed_size: Mutex<u64>,
+    linked_size: Mutex<u64>,
+    deleted_size: Mutex<u64>,
+    // Part of the added bytes that delta transfer didn't have to write
+    delta_kept_size: Mutex<u64>,
+    // Changed files copied in full because the destination can't reflink their previous version
+    delta_full_copies: Mutex<usize>,
+    copied_files: Mutex<Vec<(path::PathBuf, path::PathBuf)>>,
+    // Files left out because the destination was full
+    skipped_files: Mutex<Vec<path::PathBuf>>,
+    // Files of the last walk left out by the size and date filters
+    filtered_files: Mutex<usize>,
+    // Sockets, FIFOs, devices and unusable symbolic links of the last walk, with the reason
+    special_files: Mutex<Vec<(path::PathBuf, &'static str)>>,
+    // Files of the last backup linked to another path of the same content, as in the source
+    hard_links: Mutex<usize>,
+    // Finished files of the running backup, so that it can be resumed if it is interrupted
+    journal: Mutex<Option<Journal>>,
+    // Files of the last backup already done by the interrupted run it resumed
+    resumed: Mutex<Option<usize>>,
+    // Filesystem snapshots the sources are read from during the backup
+    source_snapshots: Vec<SourceSnapshot>,
+
+    progress_sender: Option<Sender<ProgressEvent>>,
+    progress: Mutex<ProgressState>,
+    tiers: Mutex<TierTracker>,
+    cancel: Arc<AtomicBool>,
+    // Loaded with the configuration, the error is reported when a backup starts
+    encryption: Result<Option<Cipher>, String>,
+    // Resolution of the modification times kept by the destination, found when first needed
+    time_resolution: OnceLock<Duration>,
 }
 
 #[derive(Debug)]
@@ -22,6 +89,17 @@ pub enum BackupperError {
     BkpError(String),
     WalkerError(WalkerErr),
     IoError(Vec<std::io::Error>),
+    // Destination files whose content differs from the source after the copy
+    VerifyError(Vec<path::PathBuf>),
+    // The backup was stopped through the cancel token, leaving a partial snapshot
+    Cancelled(Snapshot),
+}
+
+// What happened to a single file during the backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CopyOutcome {
+    Copied,
+    Linked,
 }
 
 impl From<WalkerErr> for BackupperError {
@@ -38,75 +116,120 @@ impl From<std::io::Error> for BackupperError {
 
 impl Backupper {
     pub fn new() -> Self {
-        let mut bkp = Self {
-            backup_config: BackupConfig::new(),
-            walker_config: WalkerConfig::new(Vec::new()),
+        Self::with_config(BackupConfig::new())
+    }
+
+    // Backupper of a saved profile, the default one when no name is given
+    pub fn for_profile(profile: Option<&str>) -> Result<Self, BackupperError> {
+        match profile {
+            None => Ok(Self::new()),
+            Some(name) => match BackupConfig::profile(name) {
+                Some(config) => Ok(Self::with_config(config)),
+                None => Err(BackupperError::BkpError(format!("No profile named {}", name))),
+            },
+        }
+    }
+
+    pub fn with_config(backup_config: BackupConfig) -> Self {
+        Self {
+            encryption: Cipher::from_config(&backup_config),
+            backup_config,
             added_size: Mutex::new(0),
             removed_size: Mutex::new(0),
-        };
-        bkp.update_rules();
-        bkp
+            delta_kept_size: Mutex::new(0),
+            delta_full_copies: Mutex::new(0),
+            linked_size: Mutex::new(0),
+            deleted_size: Mutex::new(0),
+            copied_files: Mutex::new(Vec::new()),
+            skipped_files: Mutex::new(Vec::new()),
+            filtered_files: Mutex::new(0),
+            special_files: Mutex::new(Vec::new()),
+            hard_links: Mutex::new(0),
+            journal: Mutex::new(None),
+            resumed: Mutex::new(None),
+            source_snapshots: Vec::new(),
+            progress_sender: None,
+            progress: Mutex::new(ProgressState::new()),
+            tiers: Mutex::new(TierTracker::default()),
+            cancel: Arc::new(AtomicBool::new(false)),
+            time_resolution: OnceLock::new(),
+        }
     }
 
-    pub fn update_rules(&mut self) {
-        let mut rules = Vec::new();
-        for ext in &self.backup_config.excluded_extensions {
-            let e = ext.clone();
-            rules.push(WalkerRule {
-                name: "",
-                description: None,
-                only_for: Some(rebackup::WalkerItemType::File),
-                matches: Box::new(move |x, _, _| x.extension() == Some(OsStr::new(e.as_str()))),
-                action: Box::new(|_, _, _| Ok(rebackup::WalkerRuleResult::ExcludeItem)),
-            });
-        }
-        for dir in &self.backup_config.excluded_directories {
-            let d = dir.clone();
-            rules.push(WalkerRule {
-                name: "",
-                description: None,
-                only_for: Some(rebackup::WalkerItemType::Directory),
-                matches: Box::new(move |x, _, _| {
-                    let osstr = OsStr::new(d.as_str());
-                    let p = path::Path::new(osstr);
-                    if p.is_absolute() {
-                        x.eq(p)
-                    } else if d.contains(path::MAIN_SEPARATOR) {
-                        x.ends_with(p)
-                    } else {
-                        x.file_name() == Some(osstr)
-                    }
-                }),
-                action: Box::new(|_, _, _| Ok(rebackup::WalkerRuleResult::ExcludeItem)),
-            });
-        }
-        self.walker_config = WalkerConfig::new(rules);
-        self.walker_config.drop_empty_dirs = true;
+    pub fn backup_config(&self) -> &BackupConfig {
+        &self.backup_config
+    }
+
+    // Setting the token to true stops the backup before the next file is copied
+    pub fn set_cancel_token(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = cancel;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    // Walker rules are not thread safe: they are built on demand so that the Backupper can be shared by the copy workers.
+    // Checking a single path with them counts nothing
+    pub fn walker_config(&self, source: &SourceConfig) -> WalkerConfig {
+        self.counting_walker_config(source, None)
+    }
+
+    // Same rules, counting in filtered the files skipped by the size and date filters during a walk
+    fn counting_walker_config(&self, source: &SourceConfig, filtered: Option<Arc<AtomicUsize>>) -> WalkerConfig {
+        let mut rules = self.pattern_rules(source);
+        rules.extend(self.ignore_file_rules());
+        rules.extend(self.filter_rules(filtered));
+        let mut walker_config = WalkerConfig::new(rules);
+        walker_config.drop_empty_dirs = true;
+        walker_config
     }
 
     fn get_target_files(&self) -> Result<Vec<path::PathBuf>, BackupperError> {
-        let path = path::PathBuf::from(self.backup_config.source.clone());
-        let mut data = walker::walk(&path, &self.walker_config)?;
-        #[cfg(target_os = "windows")]
-        {
-            data = data.into_iter()
-                .map(|x| {
-                    x.strip_prefix(format!("\\\\?\\{}", self.backup_config.source).as_str())
-                        .unwrap_or(&x)
-                        .to_path_buf()
-                })
-                .collect();
+        let mut data = Vec::new();
+        let mut special = Vec::new();
+        let filtered = Arc::new(AtomicUsize::new(0));
+        for source in &self.backup_config.sources {
+            let path = path::PathBuf::from(&source.path);
+            // The walker returns canonicalized paths (with the "\\?\" prefix on Windows):
+            // bring them back under the configured source so they can be mapped to the destination
+            let canonical = std::fs::canonicalize(&path)?;
+            let to_source = |x: path::PathBuf| match x.strip_prefix(&canonical) {
+                Ok(rel) => path.join(rel),
+                Err(_) => x,
+            };
+            let config = self.counting_walker_config(source, Some(Arc::clone(&filtered)));
+            let found_before = data.len();
+            let found = walk::walk(&path, &config, self.backup_config.symlinks, &mut |folder, found| {
+                self.scanning(found_before + found, &to_source(folder.to_path_buf()));
+            })?;
+            data.extend(found.files.into_iter().map(to_source));
+            special.extend(found.skipped.into_iter().map(|(x, reason)| (to_source(x), reason)));
         }
+        *self.filtered_files.lock().unwrap() = filtered.load(Ordering::Relaxed);
+        *self.special_files.lock().unwrap() = special;
+        self.sort_by_priority(&mut data);
         Ok(data)
     }
 
-    fn create_dst_path(&self, src: &path::PathBuf) -> path::PathBuf {
-        let mut dst = path::PathBuf::from(self.backup_config.destination.clone());
-        dst.push(src.strip_prefix(&self.backup_config.source).unwrap());
-        dst
+    // Where the file at rel (as returned by rel_path) is stored under a snapshot root
+    fn stored_path(&self, root: &path::Path, rel: &path::Path) -> path::PathBuf {
+        match self.cipher() {
+            Some(cipher) => root.join(cipher.encrypt_path(rel)),
+            None => root.join(rel),
+        }
     }
 
-    fn create_parent_if_not_exists(dst: &path::PathBuf) -> Result<(), std::io::Error> {
+    // Inverse of stored_path: the path relative to the source of a file stored under a snapshot root
+    fn create_src_path(root: &path::Path, dst: &path::Path, cipher: Option<&Cipher>) -> Result<path::PathBuf, std::io::Error> {
+        let stored = dst.strip_prefix(root).unwrap();
+        match cipher {
+            Some(cipher) => cipher.decrypt_path(stored),
+            None => Ok(stored.to_path_buf()),
+        }
+    }
+
+    fn create_parent_if_not_exists(dst: &path::Path) -> Result<(), std::io::Error> {
         if let Some(parent_dir) = dst.parent() {
             if !parent_dir.exists() {
                 std::fs::create_dir_all(parent_dir)?;
@@ -115,93 +238,377 @@ impl Backupper {
         Ok(())
     }
 
-    fn copy_file(&self, src: &path::PathBuf, dst: &path::PathBuf) -> Result<(), std::io::Error> {
+    fn copy_file(&self, src: &path::Path, dst: &path::Path) -> Result<(), std::io::Error> {
         Self::create_parent_if_not_exists(dst)?;
-        if dst.exists() {
-            *self.removed_size.lock().unwrap() += std::fs::metadata(dst)?.len();
-        }
-        std::fs::copy(src, dst)?;
-        *self.added_size.lock().unwrap() += std::fs::metadata(src)?.len();
+        let size = match self.cipher() {
+            Some(cipher) => {
+                let reader = File::open(src)?;
+                let permissions = reader.metadata()?.permissions();
+                atomic::write_file(&mut cipher.encrypt(reader), dst, &self.temp_file()?, Some(permissions))?
+            }
+            None => atomic::copy_file(src, dst, &self.temp_file()?)?,
+        };
+        *self.added_size.lock().unwrap() += size;
+        self.copied_files.lock().unwrap().push((src.to_path_buf(), dst.to_path_buf()));
         println!("File copied from {} to {}", src.display(), dst.display());
         Ok(())
     }
 
+    // Reuse the unchanged copy stored in the previous snapshot instead of copying the file again
+    fn link_file(&self, prev: &path::Path, src: &path::Path, dst: &path::Path) -> Result<CopyOutcome, std::io::Error> {
+        Self::create_parent_if_not_exists(dst)?;
+        match std::fs::hard_link(prev, dst) {
+            Ok(_) => {
+                *self.linked_size.lock().unwrap() += std::fs::metadata(prev)?.len();
+                println!("File linked from {} to {}", prev.display(), dst.display());
+                Ok(CopyOutcome::Linked)
+            }
+            Err(e) => {
+                // Some filesystems (e.g. FAT32 sticks) don't support hard links
+                println!("Failed to link {}: {}, copying it instead", prev.display(), e);
+                self.copy_file(src, dst)?;
+                Ok(CopyOutcome::Copied)
+            }
+        }
+    }
+
+    // previous is the manifest entry of the previous copy, when its snapshot has one
+    fn file_changed(
+        &self,
+        src_meta: &std::fs::Metadata,
+        prev_meta: &std::fs::Metadata,
+        previous: Option<&ManifestEntry>,
+    ) -> Result<bool, std::io::Error> {
+        // The previous copy is compared by the size it takes once stored
+        let stored_len = match self.cipher() {
+            Some(_) => Cipher::encrypted_len(src_meta.len()),
+            None => src_meta.len(),
+        };
+        if stored_len != prev_meta.len() {
+            return Ok(true);
+        }
+        // A copy that didn't get the time of its source is compared with the time recorded in the manifest
+        let stored_time = match previous.filter(|e| e.times_lost()) {
+            Some(entry) => entry.modified(),
+            None => prev_meta.modified()?,
+        };
+        Ok(!self.same_modified(src_meta.modified()?, stored_time))
+    }
+
     fn copy_file_if_diffs(
         &self,
-        src: &path::PathBuf,
-        dst: &path::PathBuf,
-    ) -> Result<(), std::io::Error> {
+        src: &path::Path,
+        dst: &path::Path,
+        prev: Option<&path::Path>,
+        prev_entry: Option<&ManifestEntry>,
+    ) -> Result<CopyOutcome, std::io::Error> {
         let src_meta = std::fs::metadata(src)?;
-        let dst_meta = std::fs::metadata(dst);
-        match dst_meta {
-            Ok(dm) => {
-                if src_meta.len() != dm.len() {
-                    self.copy_file(src, dst)?;
+        let prev_meta = match prev {
+            Some(p) => std::fs::metadata(p).map(|m| (p, m)),
+            None => Err(std::io::Error::from(std::io::ErrorKind::NotFound)),
+        };
+        match prev_meta {
+            Ok((prev, pm)) => {
+                if self.file_changed(&src_meta, &pm, prev_entry)? {
+                    self.copy_changed_file(src, dst, prev, &src_meta)?;
+                    // The previous version is replaced in the new snapshot
+                    *self.removed_size.lock().unwrap() += pm.len();
+                    Ok(CopyOutcome::Copied)
                 } else {
-                    let src_time = src_meta.modified()?;
-                    let dst_time = dm.modified()?;
-                    if src_time > dst_time {
-                        self.copy_file(src, dst)?;
-                    } else {
-                        println!("File already up to date: {}", src.display());
-                    }
+                    println!("File already up to date: {}", src.display());
+                    self.link_file(prev, src, dst)
                 }
             }
             Err(_) => {
                 self.copy_file(src, dst)?;
+                Ok(CopyOutcome::Copied)
             }
         }
-        Ok(())
     }
 
-    pub fn perform_backup(&self) -> Result<(), BackupperError> {
+    // meta is the metadata of the source read before the copy, which changes its access time
+    fn create_manifest_entry(
+        &self,
+        src: &path::Path,
+        meta: &std::fs::Metadata,
+        outcome: CopyOutcome,
+        previous: Option<&HashMap<String, ManifestEntry>>,
+    ) -> Result<ManifestEntry, std::io::Error> {
+        let rel = self.rel_path(src)?;
+        // Linked files are identical to the previous snapshot: reuse the hash computed back then
+        let known_hash = match (outcome, previous) {
+            (CopyOutcome::Linked, Some(entries)) => entries.get(&manifest_path(&rel)).map(|e| e.hash.clone()),
+            _ => None,
+        };
+        let hash = match known_hash {
+            Some(hash) => hash,
+            None => verify::hash_file(src)?,
+        };
+        Ok(ManifestEntry::new(&rel, meta, hash))
+    }
+
+    fn worker_count(&self) -> usize {
+        match self.backup_config.worker_threads {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+    }
+
+    // Copy the files into the snapshot, linking the ones unchanged since the previous snapshot
+    fn copy_files(
+        &self,
+        files: &[path::PathBuf],
+        snapshot: &Snapshot,
+        previous: Option<&Snapshot>,
+        previous_entries: Option<&HashMap<String, ManifestEntry>>,
+    ) -> (Vec<ManifestEntry>, Vec<std::io::Error>) {
+        let copy = |file: &path::PathBuf| {
+            let rel = self.rel_path(file)?;
+            let dst = self.stored_path(&snapshot.data_dir(), &rel);
+            if self.is_link(file) {
+                return self.copy_link(file, &dst);
+            }
+            let prev = previous.map(|p| self.stored_path(&p.data_dir(), &rel));
+            let src_meta = std::fs::metadata(file)?;
+            let metadata = self.read_metadata(file);
+            let prev_entry = previous_entries.and_then(|e| e.get(&manifest_path(&rel)));
+            self.copy_file_if_diffs(file, &dst, prev.as_deref(), prev_entry).and_then(|outcome| {
+                let mut entry = self.create_manifest_entry(file, &src_meta, outcome, previous_entries)?;
+                entry.metadata = self.copied_metadata(metadata, &entry, outcome, &dst, previous_entries);
+                Ok(entry)
+            })
+        };
+
+        // Paths sharing their content with an earlier file are linked to its copy once the files of their
+        // priority tier are copied, so that a tier is complete before the next one starts
+        let hard_links = self.hard_link_groups(files);
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+        for tier in self.files_by_tier(files) {
+            let primaries: Vec<path::PathBuf> = tier
+                .iter()
+                .filter(|i| !hard_links.contains_key(i))
+                .map(|&i| files[i].clone())
+                .collect();
+            let (tier_entries, tier_errors) = self.run_workers(&primaries, copy);
+            entries.extend(tier_entries);
+            errors.extend(tier_errors);
+
+            for &i in tier.iter().filter(|i| hard_links.contains_key(i)) {
+                if self.is_cancelled() {
+                    break;
+                }
+                let file = &files[i];
+                self.file_started(file);
+                let entry = match self.copy_hard_link(file, &files[hard_links[&i]], snapshot, &entries) {
+                    Some(entry) => Ok(entry),
+                    None => copy(file),
+                };
+                let backed_up = entry.is_ok();
+                match entry {
+                    Ok(entry) => {
+                        self.journal_file_done(&entry);
+                        entries.push(entry);
+                    }
+                    Err(e) => {
+                        eprintln!("Error copying file {:?}: {}", file, e);
+                        errors.push(e);
+                    }
+                }
+                self.file_done(file, backed_up);
+            }
+        }
+        (entries, errors)
+    }
+
+    // Process the files on a pool of worker threads, each one picking the next file of the list
+    fn run_workers<F>(&self, files: &[path::PathBuf], process: F) -> (Vec<ManifestEntry>, Vec<std::io::Error>)
+    where
+        F: Fn(&path::PathBuf) -> Result<ManifestEntry, std::io::Error> + Sync,
+    {
+        let next = AtomicUsize::new(0);
+        let entries = Mutex::new(Vec::new());
+        let errors = Mutex::new(Vec::new());
+
+        thread::scope(|scope| {
+            for _ in 0..self.worker_count().min(files.len()) {
+                scope.spawn(|| {
+                    while let Some(file) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        if self.is_cancelled() {
+                            break;
+                        }
+                        self.file_started(file);
+                        let backed_up = match process(file) {
+                            Ok(entry) => {
+                                self.journal_file_done(&entry);
+                                entries.lock().unwrap().push(entry);
+                                true
+                            }
+                            Err(e) => {
+                                eprintln!("Error copying file {:?}: {}", file, e);
+                                errors.lock().unwrap().push(e);
+                                false
+                            }
+                        };
+                        self.file_done(file, backed_up);
+                    }
+                });
+            }
+        });
+
+        (entries.into_inner().unwrap(), errors.into_inner().unwrap())
+    }
+
+    pub fn perform_backup(&self) -> Result<Snapshot, BackupperError> {
         *self.added_size.lock().unwrap() = 0;
         *self.removed_size.lock().unwrap() = 0;
+        *self.delta_kept_size.lock().unwrap() = 0;
+        *self.delta_full_copies.lock().unwrap() = 0;
+        *self.linked_size.lock().unwrap() = 0;
+        *self.deleted_size.lock().unwrap() = 0;
+        self.copied_files.lock().unwrap().clear();
+        self.skipped_files.lock().unwrap().clear();
+        *self.hard_links.lock().unwrap() = 0;
+        *self.resumed.lock().unwrap() = None;
 
-        let src = self.backup_config.source.clone();
         let dst = self.backup_config.destination.clone();
 
-        if src.is_empty() || dst.is_empty() {
+        if dst.is_empty() {
             return Err(BackupperError::BkpError(
-                "Source or destination path is not set".to_string(),
+                "Destination path is not set".to_string(),
             ));
         }
 
-        if !path::Path::new(&src).is_dir() {
-            return Err(BackupperError::BkpError(
-                "Source is not a valid directory".to_string(),
-            ));
-        }
+        self.check_sources()?;
+        self.check_patterns()?;
+        self.check_filters()?;
 
-        let mut errors = Vec::new();
+        self.check_encryption()?;
 
-        let files = self.get_target_files()?;
+        atomic::remove_temp_files(path::Path::new(&dst))?;
+        // Snapshots of other profiles sharing the destination are neither resumed nor linked to
+        let snapshots = self.profile_snapshots(path::Path::new(&dst))?;
+        let latest = snapshots.last().cloned();
+        // An interrupted run is completed in its own snapshot, from the file list it was backing up
+        let interrupted = self.interrupted_backup(latest.as_ref());
+        let (files, remaining) = match &interrupted {
+            Some(interrupted) => (interrupted.files.clone(), interrupted.remaining(self)),
+            None => {
+                let files = self.get_target_files()?;
+                (files.clone(), files)
+            }
+        };
+        let previous = match &interrupted {
+            Some(interrupted) => snapshots.into_iter().take_while(|s| s.path != interrupted.snapshot.path).last(),
+            None => latest,
+        };
+        // Files encrypted differently (or not at all) can't be reused: start again from a full copy
+        let previous = previous.filter(|p| {
+            let same = self.same_encryption(p);
+            if !same {
+                println!("Encryption settings changed since snapshot {}, copying every file", p.name());
+            }
+            same
+        });
+        let previous_entries = previous.as_ref().and_then(|p| Manifest::load(p, self.cipher())).map(|m| {
+            m.files
+                .into_iter()
+                .map(|e| (e.path.clone(), e))
+                .collect::<HashMap<_, _>>()
+        });
+        let selected = self.check_free_space(&remaining, previous.as_ref(), previous_entries.as_ref())?;
+        self.start_progress(&selected);
+        // Files skipped for lack of space still count, their tier is not complete
+        self.start_tiers(&files);
 
-        for file in files {
-            let mut file = file;
-            #[cfg(target_os = "windows")]
-            {
-                file = path::PathBuf::from(format!("{}{}{}", src, path::MAIN_SEPARATOR, file.display()));
+        let resumed = interrupted.is_some();
+        let (snapshot, done) = match interrupted {
+            Some(interrupted) => {
+                let left: HashSet<&path::PathBuf> = remaining.iter().collect();
+                for file in files.iter().filter(|f| !left.contains(f)) {
+                    self.tier_file_done(file);
+                }
+                *self.resumed.lock().unwrap() = Some(interrupted.done.len());
+                // Marked again at the end if this run doesn't complete it either
+                let _ = std::fs::remove_file(interrupted.snapshot.path.join(snapshot::PARTIAL_FILENAME));
+                (interrupted.snapshot, interrupted.done)
             }
-            let dst = self.create_dst_path(&file);
-            match self.copy_file_if_diffs(&file, &dst) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error copying file {:?}: {}", file, e);
-                    errors.push(e);
+            None => {
+                let snapshot = Snapshot::create(path::Path::new(&dst))?;
+                std::fs::write(snapshot.path.join(snapshot::PROFILE_FILENAME), &self.backup_config.name)?;
+                if let Some(cipher) = self.cipher() {
+                    cipher.info().save(&snapshot)?;
                 }
+                (snapshot, Vec::new())
             }
+        };
+        println!("Backing up into snapshot {}", snapshot.path.display());
+        self.start_journal(&snapshot, &files, resumed);
+
+        let format = self.backup_config.output_format;
+        let backed_up = (|| {
+            let (mut entries, mut errors) = match format {
+                OutputFormat::Directory => {
+                    self.copy_files(&selected, &snapshot, previous.as_ref(), previous_entries.as_ref())
+                }
+                OutputFormat::Repository => self.store_files(&selected, &snapshot),
+                _ => self.archive_files(&selected, &snapshot)?,
+            };
+            entries.extend(done);
+
+            if let (Some(previous), false) = (&previous, self.is_cancelled()) {
+                let (mut kept, mut sync_errors) = match format {
+                    OutputFormat::Directory => {
+                        self.sync_deleted_files(previous, &snapshot, &files, previous_entries.as_ref())?
+                    }
+                    OutputFormat::Repository => self.sync_deleted_entries(&files, previous_entries.as_ref()),
+                    // Archives always hold a full copy of the source, there is nothing to carry over
+                    _ => (Vec::new(), Vec::new()),
+                };
+                entries.append(&mut kept);
+                errors.append(&mut sync_errors);
+            }
+            Ok((entries, errors))
+        })();
+        let (mut entries, mut errors) = match backed_up {
+            Ok(backed_up) => backed_up,
+            // Only an interrupted backup is resumed: one stopped by an error starts over
+            Err(e) => {
+                self.finish_journal(&snapshot);
+                return Err(e);
+            }
+        };
+
+        // Marked before the manifest is saved: a snapshot with a manifest and no mark is complete
+        if self.is_cancelled() || !errors.is_empty() || !self.skipped_files.lock().unwrap().is_empty() {
+            if let Err(e) = File::create(snapshot.path.join(snapshot::PARTIAL_FILENAME)) {
+                eprintln!("Error marking the snapshot as partial: {}", e);
+                errors.push(e);
+            }
+        }
+        // Written even after errors, so the manifest lists what actually reached the destination
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        let directories = self.directory_entries(&selected, &snapshot);
+        if let Err(e) = Manifest::new(&self.recorded_config(), entries, directories).save(&snapshot, self.cipher()) {
+            eprintln!("Error writing manifest: {}", e);
+            errors.push(e);
         }
-        if errors.len() > 0 {
+        if self.is_cancelled() {
+            // As after a crash, the next run resumes the snapshot
+            self.keep_journal();
+            println!("Backup cancelled");
+            return Err(BackupperError::Cancelled(snapshot));
+        }
+        self.finish_journal(&snapshot);
+        if !errors.is_empty() {
             return Err(BackupperError::IoError(errors));
         }
 
-        Ok(())
+        Ok(snapshot)
     }
 
-    fn create_log_file(&self) -> Result<File, std::io::Error> {
-        let dst = path::PathBuf::from(self.backup_config.destination.clone())
-            .join(&self.backup_config.log_filename);
+    fn create_log_file(&self, snapshot: &Snapshot) -> Result<File, std::io::Error> {
+        let dst = snapshot.path.join(&self.backup_config.log_filename);
         Self::create_parent_if_not_exists(&dst)?;
         File::create(dst)
     }
@@ -209,20 +616,52 @@ impl Backupper {
     fn write_log(
         &self,
         log_file: &mut File,
+        snapshot: &Snapshot,
+        verification: Option<&Result<usize, BackupperError>>,
+        pruning: Option<&Result<PruneReport, BackupperError>>,
         start_clock_time: std::time::Instant,
         start_cpu_time: ProcessTime,
     ) -> Result<(), std::io::Error> {
         let added = *self.added_size.lock().unwrap();
         let removed = *self.removed_size.lock().unwrap();
+        let linked = *self.linked_size.lock().unwrap();
+        let deleted = *self.deleted_size.lock().unwrap();
         let duration = start_clock_time.elapsed();
         let cpu_duration = start_cpu_time.elapsed();
 
+        // The log is stored in the clear: it only counts the files of a snapshot whose names are encrypted
+        let list_files = !self.cipher().is_some_and(|c| c.info().file_names);
+
+        let status = if self.is_cancelled() { "cancelled" } else { "completed" };
         writeln!(
             log_file,
-            "Backup completed at: {} (total duration: {:.2?})",
+            "Backup {} at: {} (total duration: {:.2?})",
+            status,
             chrono::Utc::now(),
             duration
         )?;
+        if self.is_cancelled() {
+            let progress = self.current_progress();
+            writeln!(
+                log_file,
+                "Files processed before the cancellation: {} of {} ({} of {} bytes)",
+                progress.files_done, progress.files_total, progress.bytes_done, progress.bytes_total
+            )?;
+        }
+        writeln!(log_file, "Snapshot: {}", snapshot.name())?;
+        if let Some(done) = *self.resumed.lock().unwrap() {
+            writeln!(log_file, "Resumed an interrupted backup: {} files were already backed up", done)?;
+        }
+        if let Some(archive) = snapshot.archive() {
+            writeln!(log_file, "Archive: {}", archive.file_name().unwrap().to_string_lossy())?;
+        }
+        if let Some(cipher) = self.cipher() {
+            let names = if cipher.info().file_names { ", file names included" } else { "" };
+            writeln!(log_file, "Encryption: {:?}{}", cipher.info().mode, names)?;
+        }
+        for snapshot in self.source_snapshot_names() {
+            writeln!(log_file, "Sources read from a {}", snapshot)?;
+        }
         if added >= removed {
             let total_size = added - removed;
             writeln!(
@@ -238,20 +677,154 @@ impl Backupper {
                 total_size, removed, added
             )?;
         }
+        if self.backup_config.delta_min_size > 0 {
+            let kept = *self.delta_kept_size.lock().unwrap();
+            writeln!(
+                log_file,
+                "Bytes actually written: {} of {} backed up ({} kept from the previous snapshot by delta transfer)",
+                added - kept,
+                added,
+                kept
+            )?;
+            let full_copies = *self.delta_full_copies.lock().unwrap();
+            if full_copies > 0 {
+                writeln!(
+                    log_file,
+                    "{} changed files copied in full: delta transfer needs a destination supporting reflinks (btrfs, XFS)",
+                    full_copies
+                )?;
+            }
+        }
+        if self.backup_config.output_format == OutputFormat::Repository {
+            writeln!(log_file, "Data already in the repository: {} bytes", linked)?;
+        } else {
+            writeln!(
+                log_file,
+                "Unchanged files linked from previous snapshot: {} bytes",
+                linked
+            )?;
+        }
+        if self.backup_config.sync_mode == SyncMode::Mirror {
+            let quarantined = if self.backup_config.quarantine_deleted {
+                format!(" (kept in {})", path::Path::new(mirror::QUARANTINE_DIR).join(snapshot.name()).display())
+            } else {
+                String::new()
+            };
+            writeln!(log_file, "Files deleted from the source: {} bytes{}", deleted, quarantined)?;
+        }
+        match verification {
+            Some(Ok(verified)) => writeln!(log_file, "Verification: {} copied files match the source", verified)?,
+            Some(Err(BackupperError::VerifyError(mismatches))) => {
+                let colon = if list_files { ":" } else { "" };
+                writeln!(log_file, "Verification FAILED for {} files{}", mismatches.len(), colon)?;
+                for file in mismatches.iter().filter(|_| list_files) {
+                    writeln!(log_file, "  {}", file.display())?;
+                }
+            }
+            Some(Err(e)) => writeln!(log_file, "Verification could not be completed: {:?}", e)?,
+            None => {}
+        }
+        if self.has_priorities() {
+            for tier in self.priority_tiers() {
+                let status = if tier.is_complete() { "completed" } else { "NOT completed" };
+                writeln!(log_file, "Priority tier {}: {} ({} of {} files)", tier.name, status, tier.done, tier.files)?;
+            }
+        }
+        if self.has_file_filters() {
+            let filtered = *self.filtered_files.lock().unwrap();
+            writeln!(log_file, "Files skipped by the size and date filters: {}", filtered)?;
+        }
+        let special = self.special_files.lock().unwrap();
+        if !special.is_empty() {
+            writeln!(log_file, "Items skipped because they are not regular files: {}", special.len())?;
+            for (file, reason) in special.iter().filter(|_| list_files) {
+                writeln!(log_file, "  {} ({})", file.display(), reason)?;
+            }
+        }
+        let hard_links = *self.hard_links.lock().unwrap();
+        if hard_links > 0 {
+            writeln!(log_file, "Hard links recreated: {}", hard_links)?;
+        }
+        let skipped = self.skipped_files.lock().unwrap();
+        if !skipped.is_empty() {
+            writeln!(log_file, "Files skipped for lack of space on the destination: {}", skipped.len())?;
+            for file in skipped.iter().filter(|_| list_files) {
+                writeln!(log_file, "  {}", file.display())?;
+            }
+        }
+        match pruning {
+            Some(Ok(report)) if !report.removed.is_empty() => writeln!(
+                log_file,
+                "Expired snapshots removed: {} ({}), {} bytes freed",
+                report.removed.len(),
+                report.removed.join(", "),
+                report.freed
+            )?,
+            Some(Ok(_)) => writeln!(log_file, "Expired snapshots removed: none")?,
+            Some(Err(e)) => writeln!(log_file, "Pruning of the expired snapshots failed: {:?}", e)?,
+            None => {}
+        }
         writeln!(log_file, "CPU time used: {:.2?}", cpu_duration)?;
 
         Ok(())
     }
 
-    pub fn perform_backup_with_stats(&self) -> Result<(), BackupperError> {
+    pub fn perform_backup_with_stats(&mut self) -> Result<(), BackupperError> {
+        self.snapshot_sources();
+        let result = self.run_backup_with_stats();
+        self.release_sources();
+        self.send_progress(ProgressEvent::Finished(match &result {
+            Ok(_) => BackupOutcome::Completed,
+            Err(BackupperError::Cancelled(_)) => BackupOutcome::Cancelled,
+            Err(_) => BackupOutcome::Failed,
+        }));
+        result
+    }
+
+    fn run_backup_with_stats(&self) -> Result<(), BackupperError> {
         let start_clock_time = std::time::Instant::now();
         let start_cpu_time = ProcessTime::now();
 
-        self.perform_backup()?;
+        let snapshot = match self.perform_backup() {
+            Ok(snapshot) => snapshot,
+            // Still log what was backed up before the cancellation
+            Err(BackupperError::Cancelled(snapshot)) => {
+                let mut log_file = self.create_log_file(&snapshot)?;
+                self.write_log(&mut log_file, &snapshot, None, None, start_clock_time, start_cpu_time)?;
+                return Err(BackupperError::Cancelled(snapshot));
+            }
+            Err(e) => return Err(e),
+        };
+
+        let verification = if self.backup_config.verify_backup {
+            Some(self.verify_backup(&snapshot))
+        } else {
+            None
+        };
+        // Only a complete, verified backup can replace older snapshots
+        let pruning = match &verification {
+            Some(Err(_)) => None,
+            _ if !self.skipped_files.lock().unwrap().is_empty() => None,
+            _ if self.backup_config.retention.is_enabled() => {
+                let report = self.prune(&snapshot);
+                if let Err(e) = &report {
+                    eprintln!("Error pruning expired snapshots: {:?}", e);
+                }
+                Some(report)
+            }
+            _ => None,
+        };
 
-        match self.create_log_file() {
+        match self.create_log_file(&snapshot) {
             Ok(mut log_file) => {
-                match self.write_log(&mut log_file, start_clock_time, start_cpu_time) {
+                match self.write_log(
+                    &mut log_file,
+                    &snapshot,
+                    verification.as_ref(),
+                    pruning.as_ref(),
+                    start_clock_time,
+                    start_cpu_time,
+                ) {
                     Ok(_) => {
                         println!("Backup and log creation completed successfully.");
                     }
@@ -267,6 +840,10 @@ impl Backupper {
             }
         }
 
+        if let Some(Err(e)) = verification {
+            return Err(e);
+        }
+
         Ok(())
     }
 }
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+use super::crypto::Cipher;
+use super::manifest::{manifest_path, ManifestEntry};
+use super::snapshot::Snapshot;
+use super::verify::HashingReader;
+use super::{atomic, Backupper, BackupperError};
+use crate::config::OutputFormat;
+
+// Single compressed file receiving the files of a snapshot, one after the other
+pub enum ArchiveWriter {
+    TarZstd(tar::Builder<zstd::Encoder<'static, BufWriter<File>>>),
+    TarGzip(tar::Builder<GzEncoder<BufWriter<File>>>),
+    Zip(zip::ZipWriter<BufWriter<File>>),
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &Path, format: OutputFormat) -> Result<Self, std::io::Error> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match format {
+            OutputFormat::TarZstd => ArchiveWriter::TarZstd(tar::Builder::new(zstd::Encoder::new(file, 0)?)),
+            OutputFormat::TarGzip => {
+                ArchiveWriter::TarGzip(tar::Builder::new(GzEncoder::new(file, Compression::default())))
+            }
+            OutputFormat::Zip => ArchiveWriter::Zip(zip::ZipWriter::new(file)),
+            OutputFormat::Directory | OutputFormat::Repository => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Output format is not an archive",
+                ))
+            }
+        })
+    }
+
+    // Add a file under its stored path, returning the size and hash of its original content. The inner
+    // error only concerns the file: one that can't be read to its end is stored padded with zeros, so that
+    // the archive stays readable, and must be left out of the manifest. The outer error means that the
+    // archive itself couldn't be written and is unusable
+    pub fn append_file(
+        &mut self,
+        rel: &Path,
+        src: &Path,
+        cipher: Option<&Cipher>,
+    ) -> Result<Result<(u64, String), std::io::Error>, std::io::Error> {
+        let opened = File::open(src).and_then(|file| Ok((file.metadata()?, file)));
+        let (meta, file) = match opened {
+            Ok(opened) => opened,
+            Err(e) => return Ok(Err(e)),
+        };
+        // Stop at the size recorded in the header, even if the file grows while it is read
+        let reader = HashingReader::new(BufReader::new(file).take(meta.len()));
+        match cipher {
+            Some(cipher) => {
+                let size = Cipher::encrypted_len(meta.len());
+                let mut reader = PaddedReader::new(cipher.encrypt(reader), size);
+                self.append(rel, &meta, size, &mut reader)?;
+                Ok(reader.finish().map(|reader| reader.into_inner().finish()))
+            }
+            None => {
+                let mut reader = PaddedReader::new(reader, meta.len());
+                self.append(rel, &meta, meta.len(), &mut reader)?;
+                Ok(reader.finish().map(|reader| reader.finish()))
+            }
+        }
+    }
+
+    // Add a symbolic link under its stored path
+    pub fn append_link(&mut self, rel: &Path, src: &Path) -> Result<(), std::io::Error> {
+        let meta = src.symlink_metadata()?;
+        let target = std::fs::read_link(src)?;
+        match self {
+            ArchiveWriter::TarZstd(builder) => Self::append_tar_link(builder, rel, &meta, &target)?,
+            ArchiveWriter::TarGzip(builder) => Self::append_tar_link(builder, rel, &meta, &target)?,
+            ArchiveWriter::Zip(zip) => {
+                let mut options = FileOptions::default();
+                if let Some(time) = meta.modified().ok().and_then(zip_time) {
+                    options = options.last_modified_time(time);
+                }
+                zip.add_symlink(manifest_path(rel), target.to_string_lossy(), options)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(
+        &mut self,
+        rel: &Path,
+        meta: &std::fs::Metadata,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> Result<(), std::io::Error> {
+        match self {
+            ArchiveWriter::TarZstd(builder) => Self::append_tar(builder, rel, meta, size, reader)?,
+            ArchiveWriter::TarGzip(builder) => Self::append_tar(builder, rel, meta, size, reader)?,
+            ArchiveWriter::Zip(zip) => {
+                let mut options = FileOptions::default()
+                    .compression_method(CompressionMethod::Deflated)
+                    .large_file(size >= u32::MAX as u64);
+                if let Some(time) = meta.modified().ok().and_then(zip_time) {
+                    options = options.last_modified_time(time);
+                }
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    options = options.unix_permissions(meta.permissions().mode());
+                }
+                zip.start_file(manifest_path(rel), options)?;
+                std::io::copy(reader, zip)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_tar<W: std::io::Write>(
+        builder: &mut tar::Builder<W>,
+        rel: &Path,
+        meta: &std::fs::Metadata,
+        size: u64,
+        reader: &mut dyn Read,
+    ) -> Result<(), std::io::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(meta);
+        header.set_size(size);
+        builder.append_data(&mut header, rel, reader)
+    }
+
+    fn append_tar_link<W: std::io::Write>(
+        builder: &mut tar::Builder<W>,
+        rel: &Path,
+        meta: &std::fs::Metadata,
+        target: &Path,
+    ) -> Result<(), std::io::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(meta);
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, rel, target)
+    }
+
+    // Write the end of the archive and flush it to disk
+    pub fn finish(self) -> Result<(), std::io::Error> {
+        let file = match self {
+            ArchiveWriter::TarZstd(builder) => builder.into_inner()?.finish()?,
+            ArchiveWriter::TarGzip(builder) => builder.into_inner()?.finish()?,
+            ArchiveWriter::Zip(mut zip) => zip.finish()?,
+        };
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()
+    }
+}
+
+// Reader giving exactly size bytes: once inner fails or ends early, the rest is zeros. An entry always
+// fills the size announced in its header, whatever happens to its file while it is read
+struct PaddedReader<R> {
+    inner: R,
+    left: u64,
+    error: Option<std::io::Error>,
+}
+
+impl<R: Read> PaddedReader<R> {
+    fn new(inner: R, size: u64) -> Self {
+        Self {
+            inner,
+            left: size,
+            error: None,
+        }
+    }
+
+    // The inner reader, or why the content had to be padded
+    fn finish(self) -> Result<R, std::io::Error> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.inner),
+        }
+    }
+}
+
+impl<R: Read> Read for PaddedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(usize::try_from(self.left).unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        if self.error.is_none() {
+            match self.inner.read(&mut buf[..len]) {
+                Ok(0) => {
+                    self.error = Some(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "File shrank while it was archived",
+                    ))
+                }
+                Ok(read) => {
+                    self.left -= read as u64;
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => self.error = Some(e),
+            }
+        }
+        buf[..len].fill(0);
+        self.left -= len as u64;
+        Ok(len)
+    }
+}
+
+fn zip_time(time: std::time::SystemTime) -> Option<zip::DateTime> {
+    use chrono::{Datelike, Timelike};
+    let time = chrono::DateTime::<chrono::Local>::from(time);
+    zip::DateTime::from_date_and_time(
+        time.year().try_into().ok()?,
+        time.month() as u8,
+        time.day() as u8,
+        time.hour() as u8,
+        time.minute() as u8,
+        time.second() as u8,
+    )
+    .ok()
+}
+
+// Call f for every file stored in the archive, with its relative path and content
+pub fn for_each_file(
+    archive: &Path,
+    mut f: impl FnMut(&Path, &mut dyn Read) -> Result<(), std::io::Error>,
+) -> Result<(), std::io::Error> {
+    let file = BufReader::new(File::open(archive)?);
+    let name = archive.to_string_lossy();
+    if name.ends_with(".zip") {
+        let mut zip = zip::ZipArchive::new(file)?;
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i)?;
+            // Symbolic links are told apart by their unix mode only
+            let is_link = entry.unix_mode().is_some_and(|mode| mode & 0o170000 == 0o120000);
+            let path = match entry.enclosed_name() {
+                Some(path) if entry.is_file() && !is_link => path.to_path_buf(),
+                _ => continue,
+            };
+            f(&path, &mut entry)?;
+        }
+        return Ok(());
+    }
+    let decoder: Box<dyn Read> = if name.ends_with(".tar.gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(zstd::Decoder::new(file)?)
+    };
+    let mut tar = tar::Archive::new(decoder);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        f(&path, &mut entry)?;
+    }
+    Ok(())
+}
+
+// Hash of the original content of every file stored in the archive, by stored path
+pub fn hash_entries(archive: &Path, cipher: Option<&Cipher>) -> Result<HashMap<String, String>, std::io::Error> {
+    let mut hashes = HashMap::new();
+    for_each_file(archive, |rel, content| {
+        let content: Box<dyn Read + '_> = match cipher {
+            Some(cipher) => Box::new(cipher.decrypt(content)),
+            None => Box::new(content),
+        };
+        let mut reader = HashingReader::new(content);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        hashes.insert(manifest_path(rel), reader.finish().1);
+        Ok(())
+    })?;
+    Ok(hashes)
+}
+
+impl Backupper {
+    // Stream the files into the archive of the snapshot; the archive is written under a temporary
+    // name and only renamed into place once complete, even when the backup is cancelled
+    pub(super) fn archive_files(
+        &self,
+        files: &[PathBuf],
+        snapshot: &Snapshot,
+    ) -> Result<(Vec<ManifestEntry>, Vec<std::io::Error>), BackupperError> {
+        let format = self.backup_config.output_format;
+        let archive = snapshot.archive_path(format).unwrap();
+        let temp = self.temp_file()?;
+        let mut writer = ArchiveWriter::create(&temp, format)?;
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+
+        for file in files {
+            if self.is_cancelled() {
+                break;
+            }
+            self.file_started(file);
+            let rel = match self.rel_path(file) {
+                Ok(rel) => rel,
+                Err(e) => {
+                    eprintln!("Error archiving file {:?}: {}", file, e);
+                    errors.push(e);
+                    self.file_done(file, false);
+                    continue;
+                }
+            };
+            let stored = match self.cipher() {
+                Some(cipher) => cipher.encrypt_path(&rel),
+                None => rel.clone(),
+            };
+            let result = if self.is_link(file) {
+                // As in directory snapshots, an encrypted archive only lists the link in its manifest
+                let appended = match self.cipher() {
+                    Some(_) => Ok(()),
+                    None => writer.append_link(&stored, file),
+                };
+                appended.and_then(|_| Ok((0, self.link_entry(file)?)))
+            } else {
+                // Restore only reads the content of the archive: the manifest keeps the rest
+                let metadata = self.read_metadata(file);
+                match std::fs::metadata(file) {
+                    Ok(meta) => match writer.append_file(&stored, file, self.cipher()) {
+                        Ok(appended) => appended.map(|(size, hash)| {
+                            let mut entry = ManifestEntry::new(&rel, &meta, hash);
+                            entry.metadata = metadata;
+                            (size, entry)
+                        }),
+                        // The archive can't be trusted anymore: the backup fails rather than keeping it
+                        Err(e) => {
+                            eprintln!("Error writing archive {:?}: {}", archive, e);
+                            drop(writer);
+                            let _ = std::fs::remove_file(&temp);
+                            return Err(e.into());
+                        }
+                    },
+                    Err(e) => Err(e),
+                }
+            };
+            let backed_up = result.is_ok();
+            match result {
+                Ok((size, entry)) => {
+                    *self.added_size.lock().unwrap() += size;
+                    if entry.link.is_none() {
+                        self.copied_files.lock().unwrap().push((file.clone(), archive.join(&stored)));
+                    }
+                    println!("File archived from {} to {}", file.display(), archive.display());
+                    entries.push(entry);
+                }
+                Err(e) => {
+                    eprintln!("Error archiving file {:?}: {}", file, e);
+                    errors.push(e);
+                }
+            }
+            self.file_done(file, backed_up);
+        }
+
+        writer.finish()?;
+        atomic::persist(&temp, &archive)?;
+        Ok((entries, errors))
+    }
+}
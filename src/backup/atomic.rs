@@ -0,0 +1,173 @@
+use std::fs::{File, Permissions};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sysinfo::{Pid, System};
+
+use super::Backupper;
+
+// Folder of the destination where files are written before being renamed into place:
+// whatever it holds was left by an interrupted backup
+pub const TEMP_DIR: &str = ".tmp";
+
+static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+
+const TEMP_PREFIX: &str = ".bkp-";
+
+// Fixed-length name, so that a temporary file fits wherever its final name does. It starts with
+// the pid of the process writing it
+fn temp_name() -> String {
+    format!("{}{:08x}{:016x}", TEMP_PREFIX, std::process::id(), NEXT_TEMP.fetch_add(1, Ordering::Relaxed))
+}
+
+// Pid of the process that wrote a temporary file, from its name
+fn temp_owner(name: &str) -> Option<u32> {
+    let pid = name.strip_prefix(TEMP_PREFIX)?.get(..8)?;
+    u32::from_str_radix(pid, 16).ok()
+}
+
+// Temporary file next to dst, for files written outside of a backup destination
+pub fn temp_path(dst: &Path) -> PathBuf {
+    dst.with_file_name(temp_name())
+}
+
+// Temporary file in the temporary folder of a backup destination
+pub fn temp_file(destination: &Path) -> Result<PathBuf, std::io::Error> {
+    let dir = destination.join(TEMP_DIR);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join(temp_name()))
+}
+
+// Copy a file so that dst is either missing or complete: the data is written to the temporary
+// file (on the same filesystem), flushed to disk and only then renamed over dst
+pub fn copy_file(src: &Path, dst: &Path, temp: &Path) -> Result<u64, std::io::Error> {
+    let mut reader = File::open(src)?;
+    let permissions = reader.metadata()?.permissions();
+    write_file(&mut reader, dst, temp, Some(permissions))
+}
+
+// Same as copy_file, with the content coming from any reader
+pub fn write_file(
+    reader: &mut dyn Read,
+    dst: &Path,
+    temp: &Path,
+    permissions: Option<Permissions>,
+) -> Result<u64, std::io::Error> {
+    let result = (|| {
+        let mut writer = File::create(temp)?;
+        let size = std::io::copy(reader, &mut writer)?;
+        if let Some(permissions) = permissions {
+            writer.set_permissions(permissions)?;
+        }
+        writer.sync_all()?;
+        Ok(size)
+    })();
+    let size = match result {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = std::fs::remove_file(temp);
+            return Err(e);
+        }
+    };
+    persist(temp, dst)?;
+    Ok(size)
+}
+
+// Move a complete, already synced temporary file over its final destination
+pub fn persist(temp: &Path, dst: &Path) -> Result<(), std::io::Error> {
+    std::fs::rename(temp, dst)?;
+    sync_dir(dst);
+    Ok(())
+}
+
+// Make the rename itself durable, where the platform allows syncing a directory
+#[cfg(unix)]
+fn sync_dir(dst: &Path) {
+    if let Some(parent) = dst.parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dst: &Path) {}
+
+// Remove the temporary files left behind by interrupted backups, returning how many were found. Another
+// profile may be backing up to the same destination: the files of a process still running are kept
+pub fn remove_temp_files(destination: &Path) -> Result<usize, std::io::Error> {
+    let dir = destination.join(TEMP_DIR);
+    if !dir.is_dir() {
+        return Ok(0);
+    }
+    let mut system = System::new();
+    let mut removed = 0;
+    for entry in std::fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let owner = path.file_name().and_then(|name| temp_owner(&name.to_string_lossy()));
+        if owner.is_some_and(|pid| system.refresh_process(Pid::from_u32(pid))) {
+            continue;
+        }
+        std::fs::remove_file(&path)?;
+        removed += 1;
+    }
+    if removed > 0 {
+        println!("Removed {} leftover temporary files", removed);
+    }
+    Ok(removed)
+}
+
+impl Backupper {
+    // Temporary file to write a file of the destination through
+    pub(super) fn temp_file(&self) -> Result<PathBuf, std::io::Error> {
+        temp_file(Path::new(&self.backup_config.destination))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_names_have_a_fixed_length_and_never_repeat() {
+        let first = temp_name();
+        let second = temp_name();
+        assert_ne!(first, second);
+        assert_eq!(first.len(), second.len());
+        assert!(first.starts_with(TEMP_PREFIX));
+        assert_eq!(temp_owner(&first), Some(std::process::id()));
+        assert_eq!(temp_owner("file.txt"), None);
+    }
+
+    #[test]
+    fn temp_path_is_next_to_the_destination() {
+        let dst = Path::new("/backup/snapshot/data/a-file-with-a-long-name.txt");
+        let temp = temp_path(dst);
+        assert_eq!(temp.parent(), dst.parent());
+        assert_ne!(temp, dst);
+        assert_eq!(temp.file_name().unwrap().len(), temp_name().len());
+    }
+
+    #[test]
+    fn write_file_replaces_the_destination_through_the_temp_folder() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path();
+        let dst = destination.join("file.txt");
+        std::fs::write(&dst, "old").unwrap();
+
+        let temp = temp_file(destination).unwrap();
+        assert_eq!(temp.parent().unwrap(), destination.join(TEMP_DIR));
+        assert_eq!(write_file(&mut &b"new"[..], &dst, &temp, None).unwrap(), 3);
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "new");
+        assert!(!temp.exists());
+
+        // Only the files of processes that exited are removed
+        let running = temp_file(destination).unwrap();
+        std::fs::write(&running, "in progress").unwrap();
+        let leftover = destination.join(TEMP_DIR).join(format!("{}ffffffff{:016x}", TEMP_PREFIX, 0));
+        std::fs::write(&leftover, "leftover").unwrap();
+        assert_eq!(remove_temp_files(destination).unwrap(), 1);
+        assert!(running.exists() && !leftover.exists());
+    }
+}
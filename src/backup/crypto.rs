@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::snapshot::Snapshot;
+use super::{Backupper, BackupperError};
+use crate::config::{BackupConfig, EncryptionMode};
+
+// Environment variable holding the passphrase, which is never stored in the configuration
+pub const PASSPHRASE_ENV: &str = "EMERGENCY_BACKUP_PASSPHRASE";
+// Encryption settings of a snapshot, stored next to its data so that restore knows how to read it
+pub const ENCRYPTION_INFO_FILENAME: &str = "encryption.json";
+
+// Encrypted files are a header (magic and stream nonce) followed by chunks of
+// XChaCha20-Poly1305, each one authenticated on its own and the last one marked as such
+const MAGIC: &[u8; 4] = b"EBK1";
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const STREAM_NONCE_SIZE: usize = 19;
+const HEADER_SIZE: usize = MAGIC.len() + STREAM_NONCE_SIZE;
+// Longest file name of most filesystems. The encrypted form of a name is 40 bytes longer, then grows by a third
+// once encoded: longer ones are stored under a keyed hash starting with LONG_NAME_PREFIX, out of the base64
+// alphabet, and their real name is only kept in the manifest
+const MAX_NAME_LEN: usize = 255;
+const LONG_NAME_PREFIX: char = '~';
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionInfo {
+    pub mode: EncryptionMode,
+    // Salt of the passphrase derivation, hex encoded
+    pub salt: String,
+    pub file_names: bool,
+    // Fingerprint of the key, to tell whether the snapshot can be read (or linked to) with the current one
+    pub key_id: String,
+}
+
+impl EncryptionInfo {
+    pub fn load(snapshot: &Snapshot) -> Result<Option<Self>, std::io::Error> {
+        let path = snapshot.path.join(ENCRYPTION_INFO_FILENAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_reader(BufReader::new(File::open(path)?))?))
+    }
+
+    pub fn save(&self, snapshot: &Snapshot) -> Result<(), std::io::Error> {
+        let file = File::create(snapshot.path.join(ENCRYPTION_INFO_FILENAME))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct Cipher {
+    content_key: [u8; 32],
+    name_key: [u8; 32],
+    info: EncryptionInfo,
+    // Real names of the names stored under a hash, by stored name
+    long_names: Arc<Mutex<HashMap<String, String>>>,
+}
+
+fn subkey(master: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    Sha256::new().chain_update(label).chain_update(master).finalize().into()
+}
+
+fn invalid_data(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message.to_string())
+}
+
+// Random salt for the passphrase derivation, hex encoded
+pub fn generate_salt() -> String {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    hex::encode(salt)
+}
+
+// Write a new random key file; it must be kept away from the backup drive
+pub fn generate_key_file(path: &Path) -> Result<(), std::io::Error> {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    std::fs::write(path, key)
+}
+
+impl Cipher {
+    fn new(master: [u8; 32], mode: EncryptionMode, salt: &str, file_names: bool) -> Self {
+        Self {
+            content_key: subkey(&master, b"content"),
+            name_key: subkey(&master, b"names"),
+            info: EncryptionInfo {
+                mode,
+                salt: salt.to_string(),
+                file_names,
+                key_id: hex::encode(&subkey(&master, b"key id")[..8]),
+            },
+            long_names: Arc::default(),
+        }
+    }
+
+    fn master_key(mode: EncryptionMode, key_file: &str, passphrase_file: &str, salt: &str) -> Result<[u8; 32], String> {
+        match mode {
+            EncryptionMode::None => Err("Encryption is disabled".to_string()),
+            EncryptionMode::KeyFile => {
+                if key_file.is_empty() {
+                    return Err("No encryption key file configured".to_string());
+                }
+                let content = std::fs::read(key_file)
+                    .map_err(|e| format!("Failed to read key file {}: {}", key_file, e))?;
+                Ok(Sha256::digest(content).into())
+            }
+            EncryptionMode::Passphrase => {
+                let passphrase = match std::env::var(PASSPHRASE_ENV) {
+                    Ok(passphrase) => passphrase,
+                    Err(_) if !passphrase_file.is_empty() => std::fs::read_to_string(passphrase_file)
+                        .map_err(|e| format!("Failed to read passphrase file {}: {}", passphrase_file, e))?
+                        .trim_end_matches(['\r', '\n'])
+                        .to_string(),
+                    Err(_) => {
+                        return Err(format!("Set {} or a passphrase file to the encryption passphrase", PASSPHRASE_ENV))
+                    }
+                };
+                let salt = hex::decode(salt).unwrap_or_default();
+                if salt.len() < 8 {
+                    return Err("Missing passphrase salt, save the configuration again".to_string());
+                }
+                let mut key = [0u8; 32];
+                Argon2::default()
+                    .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+                    .map_err(|e| format!("Failed to derive the key from the passphrase: {}", e))?;
+                Ok(key)
+            }
+        }
+    }
+
+    // Cipher used to write new backups, None when encryption is disabled
+    pub fn from_config(config: &BackupConfig) -> Result<Option<Self>, String> {
+        if config.encryption == EncryptionMode::None {
+            return Ok(None);
+        }
+        let master = Self::master_key(
+            config.encryption,
+            &config.key_file,
+            &config.passphrase_file,
+            &config.encryption_salt,
+        )?;
+        Ok(Some(Self::new(
+            master,
+            config.encryption,
+            &config.encryption_salt,
+            config.encrypt_file_names,
+        )))
+    }
+
+    // Cipher able to read an existing snapshot, None if the snapshot is not encrypted
+    pub fn for_snapshot(snapshot: &Snapshot, key_file: &str, passphrase_file: &str) -> Result<Option<Self>, String> {
+        let info = match EncryptionInfo::load(snapshot).map_err(|e| e.to_string())? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let master = Self::master_key(info.mode, key_file, passphrase_file, &info.salt)?;
+        let cipher = Self::new(master, info.mode, &info.salt, info.file_names);
+        if cipher.info.key_id != info.key_id {
+            return Err(format!("Wrong key or passphrase for the backup {}", snapshot.path.display()));
+        }
+        Ok(Some(cipher))
+    }
+
+    pub fn info(&self) -> &EncryptionInfo {
+        &self.info
+    }
+
+    fn aead(key: &[u8; 32]) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(GenericArray::from_slice(key))
+    }
+
+    pub fn encrypt<R: Read>(&self, inner: R) -> EncryptReader<R> {
+        EncryptReader::new(Self::aead(&self.content_key), inner)
+    }
+
+    pub fn decrypt<R: Read>(&self, inner: R) -> DecryptReader<R> {
+        DecryptReader::new(Self::aead(&self.content_key), inner)
+    }
+
+    // Size of a file of len bytes once encrypted
+    pub fn encrypted_len(len: u64) -> u64 {
+        let chunks = len.div_ceil(CHUNK_SIZE as u64).max(1);
+        HEADER_SIZE as u64 + len + chunks * TAG_SIZE as u64
+    }
+
+    // Keyed hash identifying a chunk of the repository
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        let digest = Sha256::new()
+            .chain_update(b"chunk")
+            .chain_update(self.name_key)
+            .chain_update(data)
+            .finalize();
+        hex::encode(digest)
+    }
+
+    // Names are encrypted deterministically (the nonce is derived from the name), so that an
+    // unchanged file keeps the same stored path and can be linked to the previous snapshot
+    fn encrypt_name(&self, name: &OsStr) -> String {
+        let name = name.to_string_lossy();
+        if Self::is_long_name(&name) {
+            let stored = self.long_name(&name);
+            self.long_names.lock().unwrap().insert(stored.clone(), name.to_string());
+            return stored;
+        }
+        let digest = Sha256::new()
+            .chain_update(b"nonce")
+            .chain_update(self.name_key)
+            .chain_update(name.as_bytes())
+            .finalize();
+        let nonce = XNonce::from_slice(&digest[..24]);
+        let mut stored = nonce.to_vec();
+        stored.extend(Self::aead(&self.name_key).encrypt(nonce, name.as_bytes()).unwrap());
+        URL_SAFE_NO_PAD.encode(stored)
+    }
+
+    fn is_long_name(name: &str) -> bool {
+        base64::encoded_len(24 + name.len() + TAG_SIZE, false).is_none_or(|len| len > MAX_NAME_LEN)
+    }
+
+    fn long_name(&self, name: &str) -> String {
+        let digest = Sha256::new()
+            .chain_update(b"long name")
+            .chain_update(self.name_key)
+            .chain_update(name.as_bytes())
+            .finalize();
+        format!("{}{}", LONG_NAME_PREFIX, URL_SAFE_NO_PAD.encode(digest))
+    }
+
+    // Learn the real names of the names stored under a hash from the paths of a manifest
+    pub fn add_long_names<'a>(&self, paths: impl Iterator<Item = &'a str>) {
+        if !self.info.file_names {
+            return;
+        }
+        let mut long_names = self.long_names.lock().unwrap();
+        for name in paths.flat_map(|path| path.split('/')).filter(|name| Self::is_long_name(name)) {
+            long_names.insert(self.long_name(name), name.to_string());
+        }
+    }
+
+    fn decrypt_name(&self, stored: &OsStr) -> Result<String, std::io::Error> {
+        let stored = stored.to_string_lossy();
+        if stored.starts_with(LONG_NAME_PREFIX) {
+            return match self.long_names.lock().unwrap().get(stored.as_ref()) {
+                Some(name) => Ok(name.clone()),
+                None => Err(invalid_data("Long file name missing from the manifest of the backup")),
+            };
+        }
+        let stored = URL_SAFE_NO_PAD
+            .decode(stored.as_bytes())
+            .map_err(|_| invalid_data("Not an encrypted file name"))?;
+        if stored.len() < 24 {
+            return Err(invalid_data("Not an encrypted file name"));
+        }
+        let (nonce, encrypted) = stored.split_at(24);
+        let name = Self::aead(&self.name_key)
+            .decrypt(XNonce::from_slice(nonce), encrypted)
+            .map_err(|_| invalid_data("Failed to decrypt file name: wrong key or corrupted backup"))?;
+        String::from_utf8(name).map_err(|_| invalid_data("Invalid file name"))
+    }
+
+    // Path stored in the backup for a path relative to the source
+    pub fn encrypt_path(&self, rel: &Path) -> PathBuf {
+        if !self.info.file_names {
+            return rel.to_path_buf();
+        }
+        rel.components().map(|c| self.encrypt_name(c.as_os_str())).collect()
+    }
+
+    // Path relative to the source of a path stored in the backup
+    pub fn decrypt_path(&self, stored: &Path) -> Result<PathBuf, std::io::Error> {
+        if !self.info.file_names {
+            return Ok(stored.to_path_buf());
+        }
+        stored.components().map(|c| self.decrypt_name(c.as_os_str())).collect()
+    }
+}
+
+// Read up to size bytes into buffer, stopping early only at the end of the reader
+fn fill(inner: &mut impl Read, buffer: &mut Vec<u8>, size: usize) -> Result<(), std::io::Error> {
+    let missing = size.saturating_sub(buffer.len()) as u64;
+    inner.take(missing).read_to_end(buffer)?;
+    Ok(())
+}
+
+// Reader returning the encrypted form of everything read from inner
+pub struct EncryptReader<R> {
+    inner: R,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    plain: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> EncryptReader<R> {
+    fn new(aead: XChaCha20Poly1305, inner: R) -> Self {
+        let mut nonce = [0u8; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&nonce);
+        Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(aead, GenericArray::from_slice(&nonce))),
+            plain: Vec::new(),
+            out,
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn next_chunk(&mut self) -> Result<(), std::io::Error> {
+        let mut encryptor = match self.encryptor.take() {
+            Some(encryptor) => encryptor,
+            None => return Ok(()),
+        };
+        // One byte more than a chunk tells whether this chunk is the last one
+        fill(&mut self.inner, &mut self.plain, CHUNK_SIZE + 1)?;
+        let chunk = if self.plain.len() <= CHUNK_SIZE {
+            let chunk = encryptor.encrypt_last(&self.plain[..]);
+            self.plain.clear();
+            chunk
+        } else {
+            let chunk = encryptor.encrypt_next(&self.plain[..CHUNK_SIZE]);
+            self.plain.drain(..CHUNK_SIZE);
+            self.encryptor = Some(encryptor);
+            chunk
+        };
+        self.out = chunk.map_err(|_| std::io::Error::other("Encryption failed"))?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for EncryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.out.len() {
+            if self.encryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let read = buf.len().min(self.out.len() - self.pos);
+        buf[..read].copy_from_slice(&self.out[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+// Reader returning the decrypted content of an encrypted file, failing if it was altered or truncated
+pub struct DecryptReader<R> {
+    inner: R,
+    aead: Option<XChaCha20Poly1305>,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    encrypted: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn new(aead: XChaCha20Poly1305, inner: R) -> Self {
+        Self {
+            inner,
+            aead: Some(aead),
+            decryptor: None,
+            encrypted: Vec::new(),
+            out: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<(), std::io::Error> {
+        if let Some(aead) = self.aead.take() {
+            let mut header = [0u8; HEADER_SIZE];
+            self.inner
+                .read_exact(&mut header)
+                .map_err(|_| invalid_data("Not an encrypted backup file"))?;
+            if &header[..MAGIC.len()] != MAGIC {
+                return Err(invalid_data("Not an encrypted backup file"));
+            }
+            let nonce = GenericArray::from_slice(&header[MAGIC.len()..]);
+            self.decryptor = Some(DecryptorBE32::from_aead(aead, nonce));
+        }
+        let mut decryptor = match self.decryptor.take() {
+            Some(decryptor) => decryptor,
+            None => return Ok(()),
+        };
+        fill(&mut self.inner, &mut self.encrypted, CHUNK_SIZE + TAG_SIZE + 1)?;
+        let chunk = if self.encrypted.len() <= CHUNK_SIZE + TAG_SIZE {
+            let chunk = decryptor.decrypt_last(&self.encrypted[..]);
+            self.encrypted.clear();
+            chunk
+        } else {
+            let chunk = decryptor.decrypt_next(&self.encrypted[..CHUNK_SIZE + TAG_SIZE]);
+            self.encrypted.drain(..CHUNK_SIZE + TAG_SIZE);
+            self.decryptor = Some(decryptor);
+            chunk
+        };
+        self.out = chunk.map_err(|_| invalid_data("Decryption failed: wrong key or corrupted file"))?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.out.len() {
+            if self.aead.is_none() && self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let read = buf.len().min(self.out.len() - self.pos);
+        buf[..read].copy_from_slice(&self.out[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Backupper {
+    // Cipher of the configuration, None when encryption is disabled or its key is unavailable
+    pub(super) fn cipher(&self) -> Option<&Cipher> {
+        self.encryption.as_ref().ok().and_then(|c| c.as_ref())
+    }
+
+    // Fail early when encryption is enabled but its key can't be loaded
+    pub(super) fn check_encryption(&self) -> Result<(), BackupperError> {
+        match &self.encryption {
+            Ok(_) => Ok(()),
+            Err(e) => Err(BackupperError::BkpError(e.clone())),
+        }
+    }
+
+    // Path relative to the source of a file stored with the current settings
+    pub(super) fn stored_to_source(&self, stored: &Path) -> Result<PathBuf, std::io::Error> {
+        match self.cipher() {
+            Some(cipher) => cipher.decrypt_path(stored),
+            None => Ok(stored.to_path_buf()),
+        }
+    }
+
+    // Whether the files of a snapshot were stored with the same encryption as new ones,
+    // the condition for linking to them
+    pub(super) fn same_encryption(&self, snapshot: &Snapshot) -> bool {
+        match EncryptionInfo::load(snapshot) {
+            Ok(info) => info.as_ref() == self.cipher().map(|c| c.info()),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher {
+        Cipher::new([7; 32], EncryptionMode::KeyFile, "", true)
+    }
+
+    #[test]
+    fn names_round_trip_and_are_deterministic() {
+        let cipher = cipher();
+        let stored = cipher.encrypt_name(OsStr::new("report.pdf"));
+        assert_ne!(stored, "report.pdf");
+        assert_eq!(stored, cipher.encrypt_name(OsStr::new("report.pdf")));
+        assert_eq!(cipher.decrypt_name(OsStr::new(&stored)).unwrap(), "report.pdf");
+        assert!(cipher.decrypt_name(OsStr::new("not-encrypted")).is_err());
+    }
+
+    #[test]
+    fn content_round_trips_and_tampering_is_detected() {
+        let cipher = cipher();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut encrypted = Vec::new();
+        cipher.encrypt(&content[..]).read_to_end(&mut encrypted).unwrap();
+        assert_eq!(encrypted.len() as u64, Cipher::encrypted_len(content.len() as u64));
+
+        let mut decrypted = Vec::new();
+        cipher.decrypt(&encrypted[..]).read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, content);
+
+        // A changed byte or a missing last chunk makes the whole file unreadable
+        let mut changed = encrypted.clone();
+        changed[HEADER_SIZE + 5] ^= 1;
+        assert!(cipher.decrypt(&changed[..]).read_to_end(&mut Vec::new()).is_err());
+        let truncated = &encrypted[..encrypted.len() - 10 - TAG_SIZE];
+        assert!(cipher.decrypt(truncated).read_to_end(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn stored_names_fit_the_name_limit() {
+        let cipher = cipher();
+        for len in [1, 150, 151, 152, 200, 255] {
+            let name = "n".repeat(len);
+            let stored = cipher.encrypt_name(OsStr::new(&name));
+            assert!(stored.len() <= MAX_NAME_LEN, "{} bytes stored as {}", len, stored.len());
+            assert_eq!(stored.starts_with(LONG_NAME_PREFIX), len > 151);
+            assert_eq!(cipher.decrypt_name(OsStr::new(&stored)).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn long_names_are_learnt_from_the_manifest_paths() {
+        let long = "l".repeat(200);
+        let rel = Path::new("folder").join(&long).join("file.txt");
+        let stored = cipher().encrypt_path(&rel);
+
+        let restoring = cipher();
+        assert!(restoring.decrypt_path(&stored).is_err());
+        restoring.add_long_names([format!("folder/{}/file.txt", long).as_str()].into_iter());
+        assert_eq!(restoring.decrypt_path(&stored).unwrap(), rel);
+    }
+}
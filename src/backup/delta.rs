@@ -0,0 +1,378 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use super::atomic;
+use super::Backupper;
+
+// Compared blocks are a multiple of the 4 KiB blocks of most filesystems, so that the unchanged
+// ones stay where they are in the copy of the previous version
+const MIN_BLOCK_SIZE: usize = 4096;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+// Source data that differs from the previous version is written out once this much is pending
+const MAX_PENDING: usize = 1024 * 1024;
+
+// As rsync, blocks of about the square root of the file size
+fn block_size(len: u64) -> usize {
+    let size = (len as f64).sqrt() as usize;
+    (size.div_ceil(MIN_BLOCK_SIZE) * MIN_BLOCK_SIZE).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+// rsync's weak checksum, which slides over the data one byte at a time
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut sum = Rolling { a: 0, b: 0, len: data.len() as u32 };
+        for (i, &x) in data.iter().enumerate() {
+            sum.a = sum.a.wrapping_add(x as u32);
+            sum.b = sum.b.wrapping_add((data.len() - i) as u32 * x as u32);
+        }
+        sum
+    }
+
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+// Strong hash and offset of the full blocks of a file, by weak checksum
+type Signature = HashMap<u32, Vec<([u8; 32], u64)>>;
+
+fn signature(path: &Path, block: usize) -> Result<Signature, std::io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut data = vec![0; block];
+    let mut blocks = Signature::new();
+    let mut offset = 0;
+    loop {
+        // The last partial block is never matched
+        match reader.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let weak = Rolling::new(&data).digest();
+        blocks.entry(weak).or_default().push((Sha256::digest(&data).into(), offset));
+        offset += block as u64;
+    }
+    Ok(blocks)
+}
+
+// Read until buf holds len bytes, false if the reader ends first
+fn fill(reader: &mut impl Read, buf: &mut Vec<u8>, len: usize) -> Result<bool, std::io::Error> {
+    while buf.len() < len {
+        let start = buf.len();
+        buf.resize(len.max(start + 64 * 1024), 0);
+        let read = reader.read(&mut buf[start..])?;
+        buf.truncate(start + read);
+        if read == 0 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Make dst share every block of src, on filesystems able to (btrfs, XFS): nothing is written until it changes
+#[cfg(target_os = "linux")]
+fn reflink(src: &File, dst: &File) -> bool {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: both descriptors stay open during the call, FICLONE only reads its argument
+    unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE as _, src.as_raw_fd()) == 0 }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_src: &File, _dst: &File) -> bool {
+    false
+}
+
+// Reflinked copy of the previous version being turned into the source
+struct Patch {
+    file: File,
+    written: u64,
+}
+
+impl Patch {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), std::io::Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+}
+
+// Turn out, a copy of prev sharing its blocks, into the data of reader (len bytes): look for the blocks of prev
+// in it with the rolling checksum, and only write the parts that are not already at the same offset.
+// Returns the number of bytes written
+fn patch(reader: &mut impl Read, len: u64, prev: &Path, out: File) -> Result<u64, std::io::Error> {
+    let mut prev_file = File::open(prev)?;
+    let block = block_size(prev_file.metadata()?.len());
+    let blocks = signature(prev, block)?;
+    let mut patch = Patch { file: out, written: 0 };
+
+    // Source data from offset base: the window compared with the blocks is buf[pos..pos + block],
+    // buf[..pos] differs from the previous version
+    let mut buf = Vec::new();
+    let mut base = 0;
+    let mut pos = 0;
+    let mut sum: Option<Rolling> = None;
+    while fill(reader, &mut buf, pos + block)? {
+        let window = &buf[pos..pos + block];
+        let weak = sum.get_or_insert_with(|| Rolling::new(window)).digest();
+        let offset = base + pos as u64;
+        let matched = blocks.get(&weak).and_then(|candidates| {
+            let strong: [u8; 32] = Sha256::digest(window).into();
+            let mut offsets = candidates.iter().filter(|(hash, _)| *hash == strong).map(|(_, o)| *o);
+            let first = offsets.next()?;
+            Some(first == offset || offsets.any(|o| o == offset))
+        });
+        match matched {
+            Some(in_place) => {
+                patch.write_at(base, &buf[..pos])?;
+                // A block found elsewhere in the previous version moved: it is written at its new offset
+                if !in_place {
+                    patch.write_at(offset, &buf[pos..pos + block])?;
+                }
+                buf.drain(..pos + block);
+                base = offset + block as u64;
+                pos = 0;
+                sum = None;
+            }
+            None => {
+                if pos >= MAX_PENDING {
+                    patch.write_at(base, &buf[..pos])?;
+                    buf.drain(..pos);
+                    base += pos as u64;
+                    pos = 0;
+                }
+                if !fill(reader, &mut buf, pos + block + 1)? {
+                    break;
+                }
+                if let Some(sum) = &mut sum {
+                    sum.roll(buf[pos], buf[pos + block]);
+                }
+                pos += 1;
+            }
+        }
+    }
+    // What is left never fills a block: it often starts with the unchanged end of the file
+    let mut previous = Vec::new();
+    prev_file.seek(SeekFrom::Start(base))?;
+    prev_file.take(buf.len() as u64).read_to_end(&mut previous)?;
+    let same = buf.iter().zip(&previous).take_while(|(a, b)| a == b).count();
+    patch.write_at(base + same as u64, &buf[same..])?;
+    patch.file.set_len(len)?;
+    patch.file.sync_all()?;
+    Ok(patch.written)
+}
+
+// Write src to dst through temp as rsync --inplace would, patching a reflinked copy of prev. Without reflinks,
+// copying prev would write more than src itself: src is then written out in one pass.
+// Returns the size of src, the number of bytes written to dst and whether prev could be reflinked
+pub fn delta_copy(src: &Path, prev: &Path, dst: &Path, temp: &Path) -> Result<(u64, u64, bool), std::io::Error> {
+    let result = (|| {
+        let file = File::open(src)?;
+        // Stop at the current size, even if the file grows while it is read
+        let len = file.metadata()?.len();
+        let mut reader = BufReader::new(file.take(len));
+
+        let out = OpenOptions::new().write(true).create(true).truncate(true).open(temp)?;
+        if reflink(&File::open(prev)?, &out) {
+            return Ok((len, patch(&mut reader, len, prev, out)?, true));
+        }
+        let mut out = BufWriter::new(out);
+        let written = std::io::copy(&mut reader, &mut out)?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok((len, written, false))
+    })();
+    match result {
+        Ok(sizes) => {
+            atomic::persist(temp, dst)?;
+            Ok(sizes)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(temp);
+            Err(e)
+        }
+    }
+}
+
+impl Backupper {
+    // Copy a file changed since the previous snapshot, by delta transfer from its previous copy when it is large
+    // enough; an encrypted copy is entirely different after any change, so it is always copied in full
+    pub(super) fn copy_changed_file(
+        &self,
+        src: &Path,
+        dst: &Path,
+        prev: &Path,
+        src_meta: &std::fs::Metadata,
+    ) -> Result<(), std::io::Error> {
+        let min_size = self.backup_config.delta_min_size;
+        if min_size == 0 || src_meta.len() < min_size || self.cipher().is_some() {
+            return self.copy_file(src, dst);
+        }
+        Self::create_parent_if_not_exists(dst)?;
+        let (size, written, reflinked) = delta_copy(src, prev, dst, &self.temp_file()?)?;
+        *self.added_size.lock().unwrap() += size;
+        *self.delta_kept_size.lock().unwrap() += size.saturating_sub(written);
+        self.copied_files.lock().unwrap().push((src.to_path_buf(), dst.to_path_buf()));
+        if reflinked {
+            println!(
+                "File updated from {} to {} by delta transfer: {} of {} bytes written",
+                src.display(),
+                dst.display(),
+                written,
+                size
+            );
+        } else {
+            *self.delta_full_copies.lock().unwrap() += 1;
+            println!(
+                "File copied from {} to {}: the destination doesn't support reflinks, delta transfer is not possible",
+                src.display(),
+                dst.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupConfig;
+
+    #[test]
+    fn rolling_the_checksum_matches_computing_it_again() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let block = 4096;
+        let mut sum = Rolling::new(&data[..block]);
+        for start in 1..data.len() - block {
+            sum.roll(data[start - 1], data[start + block - 1]);
+            assert_eq!(sum.digest(), Rolling::new(&data[start..start + block]).digest(), "offset {}", start);
+        }
+    }
+
+    #[test]
+    fn blocks_are_a_multiple_of_the_filesystem_blocks() {
+        assert_eq!(block_size(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(1 << 30), 32 * 1024);
+        assert_eq!(block_size(u64::MAX), MAX_BLOCK_SIZE);
+        assert_eq!(block_size(100_000_000) % MIN_BLOCK_SIZE, 0);
+    }
+
+    // Patch a plain copy of previous into current, returning the bytes written
+    fn patched(name: &str, previous: &[u8], current: &[u8]) -> u64 {
+        let dir = tempfile::tempdir().unwrap();
+        let (prev, out) = (dir.path().join("prev"), dir.path().join("out"));
+        std::fs::write(&prev, previous).unwrap();
+        std::fs::copy(&prev, &out).unwrap();
+        let file = OpenOptions::new().write(true).open(&out).unwrap();
+        let written = patch(&mut &current[..], current.len() as u64, &prev, file).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), current, "{}", name);
+        written
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len as u64).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect()
+    }
+
+    #[test]
+    fn unchanged_blocks_are_not_written() {
+        let previous = content(1_000_000);
+        assert_eq!(patched("same", &previous, &previous), 0);
+
+        let mut changed = previous.clone();
+        changed[500_000] ^= 0xff;
+        let written = patched("changed", &previous, &changed);
+        assert!(written > 0 && written <= block_size(previous.len() as u64) as u64, "{}", written);
+
+        let mut appended = previous.clone();
+        appended.extend_from_slice(b"appended at the end");
+        assert_eq!(patched("appended", &previous, &appended), 19);
+
+        assert_eq!(patched("truncated", &previous, &previous[..600_000]), 0);
+    }
+
+    #[test]
+    fn shifted_and_new_content_is_rebuilt() {
+        let previous = content(300_000);
+        let mut inserted = previous[..100_000].to_vec();
+        inserted.extend_from_slice(b"inserted");
+        inserted.extend_from_slice(&previous[100_000..]);
+        patched("inserted", &previous, &inserted);
+
+        patched("shrunk", &previous, &previous[1000..]);
+        patched("empty", &previous, &[]);
+        patched("grown", &previous[..5000], &previous);
+        patched("different", &previous, &content(400_000).iter().map(|b| b ^ 0x55).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_previous_version_is_reflinked_when_the_destination_can() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path();
+        let (prev, src, dst, temp) = (dir.join("prev"), dir.join("src"), dir.join("dst"), dir.join("temp"));
+        let previous = content(1_000_000);
+        let mut current = previous.clone();
+        current[500_000] ^= 0xff;
+        std::fs::write(&prev, &previous).unwrap();
+        std::fs::write(&src, &current).unwrap();
+
+        let (size, written, reflinked) = delta_copy(&src, &prev, &dst, &temp).unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), current);
+        assert_eq!(std::fs::read(&prev).unwrap(), previous);
+        assert!(!temp.exists());
+        assert_eq!(size, current.len() as u64);
+        // Only the changed block goes over a reflink (btrfs, XFS), elsewhere the file is written in full
+        if reflinked {
+            assert!(written > 0 && written <= block_size(size) as u64, "{}", written);
+        } else {
+            assert_eq!(written, size);
+        }
+    }
+
+    #[test]
+    fn only_files_above_the_minimum_size_go_through_delta_transfer() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path();
+        let mut config = BackupConfig::with_defaults("test");
+        config.destination = dir.join("destination").to_string_lossy().into_owned();
+        config.delta_min_size = 100_000;
+        let backupper = Backupper::with_config(config);
+
+        for (name, len) in [("small", 50_000), ("large", 300_000)] {
+            let (prev, src, dst) = (dir.join(format!("{}.prev", name)), dir.join(name), dir.join("copy").join(name));
+            let previous = content(len);
+            let mut current = previous.clone();
+            current[len / 2] ^= 0xff;
+            std::fs::write(&prev, &previous).unwrap();
+            std::fs::write(&src, &current).unwrap();
+            backupper.copy_changed_file(&src, &dst, &prev, &std::fs::metadata(&src).unwrap()).unwrap();
+            assert_eq!(std::fs::read(&dst).unwrap(), current);
+        }
+        assert_eq!(*backupper.added_size.lock().unwrap(), 350_000);
+        assert_eq!(backupper.copied_files.lock().unwrap().len(), 2);
+        // The small file is copied in full; the large one too when the destination has no reflinks
+        let kept = *backupper.delta_kept_size.lock().unwrap();
+        let full_copies = *backupper.delta_full_copies.lock().unwrap();
+        match full_copies {
+            0 => assert!(kept >= 300_000 - block_size(300_000) as u64, "{}", kept),
+            full_copies => assert_eq!((full_copies, kept), (1, 0)),
+        }
+    }
+}
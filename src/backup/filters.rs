@@ -0,0 +1,137 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{Local, NaiveDate, TimeZone};
+use rebackup::{WalkerItemType, WalkerRule, WalkerRuleResult};
+
+use super::{Backupper, BackupperError};
+use crate::config::BackupConfig;
+
+// Format of the modification date thresholds of the configuration
+pub const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// Start of the given day in local time, None for an empty threshold
+pub fn parse_date(date: &str) -> Result<Option<SystemTime>, String> {
+    let date = date.trim();
+    if date.is_empty() {
+        return Ok(None);
+    }
+    let day = NaiveDate::parse_from_str(date, DATE_FORMAT).map_err(|_| format!("{} is not a YYYY-MM-DD date", date))?;
+    let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+    match Local.from_local_datetime(&midnight).earliest() {
+        Some(time) => Ok(Some(time.into())),
+        None => Err(format!("{} doesn't exist in the local time zone", date)),
+    }
+}
+
+// Size and modification date limits of the files to back up
+#[derive(Clone, Copy)]
+struct FileFilters {
+    min_size: u64,
+    max_size: Option<u64>,
+    modified_after: Option<SystemTime>,
+    modified_before: Option<SystemTime>,
+}
+
+impl FileFilters {
+    fn from_config(config: &BackupConfig) -> Result<Self, String> {
+        Ok(Self {
+            min_size: config.min_file_size,
+            max_size: Some(config.max_file_size).filter(|max| *max > 0),
+            modified_after: parse_date(&config.modified_after)?,
+            modified_before: parse_date(&config.modified_before)?,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.min_size > 0 || self.max_size.is_some() || self.modified_after.is_some() || self.modified_before.is_some()
+    }
+
+    fn skips(&self, meta: &std::fs::Metadata) -> bool {
+        let modified = meta.modified().ok();
+        meta.len() < self.min_size
+            || self.max_size.is_some_and(|max| meta.len() > max)
+            || matches!((self.modified_after, modified), (Some(after), Some(m)) if m < after)
+            || matches!((self.modified_before, modified), (Some(before), Some(m)) if m >= before)
+    }
+}
+
+impl Backupper {
+    pub fn has_file_filters(&self) -> bool {
+        FileFilters::from_config(&self.backup_config).is_ok_and(|f| f.is_enabled())
+    }
+
+    pub(super) fn check_filters(&self) -> Result<(), BackupperError> {
+        let config = &self.backup_config;
+        FileFilters::from_config(config).map_err(BackupperError::BkpError)?;
+        if config.max_file_size > 0 && config.min_file_size > config.max_file_size {
+            return Err(BackupperError::BkpError(
+                "The minimum file size is larger than the maximum file size".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Walker rule skipping the files outside of the size and date limits, counted in filtered when given;
+    // it comes last so that files already excluded by another rule are not counted
+    pub(super) fn filter_rules(&self, filtered: Option<Arc<AtomicUsize>>) -> Vec<WalkerRule> {
+        let filters = match FileFilters::from_config(&self.backup_config) {
+            Ok(filters) if filters.is_enabled() => filters,
+            // Invalid dates are reported by check_filters
+            _ => return Vec::new(),
+        };
+        vec![WalkerRule {
+            name: "size and date filters",
+            description: None,
+            only_for: Some(WalkerItemType::File),
+            matches: Box::new(move |x, _, _| std::fs::metadata(x).is_ok_and(|meta| filters.skips(&meta))),
+            action: Box::new(move |_, _, _| {
+                if let Some(filtered) = &filtered {
+                    filtered.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(WalkerRuleResult::ExcludeItem)
+            }),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dates_are_the_start_of_the_local_day() {
+        assert_eq!(parse_date("").unwrap(), None);
+        assert_eq!(parse_date("  ").unwrap(), None);
+        let day = parse_date(" 2024-02-29 ").unwrap().unwrap();
+        let next = parse_date("2024-03-01").unwrap().unwrap();
+        let expected = Local.with_ymd_and_hms(2024, 2, 29, 0, 0, 0).earliest().unwrap();
+        assert_eq!(day, SystemTime::from(expected));
+        assert!(next > day);
+    }
+
+    #[test]
+    fn invalid_dates_are_rejected() {
+        for date in ["2023-02-29", "2024-13-01", "01/02/2024", "2024-1-1x", "yesterday"] {
+            assert!(parse_date(date).is_err(), "{} accepted", date);
+        }
+    }
+
+    #[test]
+    fn limits_skip_files_outside_of_them() {
+        let config = BackupConfig {
+            min_file_size: 2,
+            max_file_size: 4,
+            ..BackupConfig::with_defaults("test")
+        };
+        let filters = FileFilters::from_config(&config).unwrap();
+        assert!(filters.is_enabled());
+        let file = tempfile::NamedTempFile::new().unwrap();
+        for (content, skipped) in [("a", true), ("ab", false), ("abcd", false), ("abcde", true)] {
+            std::fs::write(file.path(), content).unwrap();
+            assert_eq!(filters.skips(&std::fs::metadata(file.path()).unwrap()), skipped, "{}", content);
+        }
+        assert!(!FileFilters::from_config(&BackupConfig::with_defaults("test")).unwrap().is_enabled());
+    }
+}
@@ -0,0 +1,142 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use ignore::gitignore::Gitignore;
+use rebackup::{WalkerItemType, WalkerRule, WalkerRuleResult};
+
+use super::Backupper;
+
+// Ignore files read in every walked directory, a later one taking precedence over an earlier one
+pub const IGNORE_FILES: [&str; 2] = [".gitignore", ".backupignore"];
+
+// Ignore files of the directories met so far, each one read only once per walk
+#[derive(Default)]
+struct IgnoreCache {
+    dirs: HashMap<PathBuf, Vec<(PathBuf, Gitignore)>>,
+}
+
+impl IgnoreCache {
+    fn matchers(&mut self, dir: &Path) -> &[(PathBuf, Gitignore)] {
+        self.dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            IGNORE_FILES
+                .iter()
+                .map(|name| dir.join(name))
+                .filter(|file| file.is_file())
+                .map(|file| {
+                    let (matcher, error) = Gitignore::new(&file);
+                    if let Some(e) = error {
+                        eprintln!("Error reading {}: {}", file.display(), e);
+                    }
+                    (file, matcher)
+                })
+                .collect()
+        })
+    }
+
+    // Ignore file excluding an item of the source: as with git the deepest directory decides,
+    // so a "!pattern" of a subfolder re-includes what a parent folder ignores
+    fn excluded_by(&mut self, item: &Path, is_dir: bool, source: &Path) -> Option<PathBuf> {
+        for dir in item.ancestors().skip(1).take_while(|dir| dir.starts_with(source)) {
+            for (file, matcher) in self.matchers(dir).iter().rev() {
+                let matched = matcher.matched(item, is_dir);
+                if matched.is_ignore() {
+                    return Some(file.clone());
+                } else if matched.is_whitelist() {
+                    return None;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl Backupper {
+    // Walker rules excluding what the .gitignore and .backupignore files of each directory ignore in its subtree
+    pub(super) fn ignore_file_rules(&self) -> Vec<WalkerRule> {
+        if !self.backup_config.use_ignore_files {
+            return Vec::new();
+        }
+        let cache = Rc::new(RefCell::new(IgnoreCache::default()));
+        [WalkerItemType::Directory, WalkerItemType::File, WalkerItemType::Symlink]
+            .into_iter()
+            .map(|item_type| {
+                let cache = Rc::clone(&cache);
+                WalkerRule {
+                    name: "ignore files",
+                    description: None,
+                    only_for: Some(item_type),
+                    matches: Box::new(move |x, _, source| {
+                        cache.borrow_mut().excluded_by(x, item_type == WalkerItemType::Directory, source).is_some()
+                    }),
+                    action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+                }
+            })
+            .collect()
+    }
+
+    // Ignore file that kept a file of the snapshot (path as returned by rel_path) out of the backup,
+    // either directly or through one of its folders
+    pub(super) fn ignored_by(&self, rel: &Path) -> Option<PathBuf> {
+        if !self.backup_config.use_ignore_files {
+            return None;
+        }
+        let (source, src) = self.source_path(rel)?;
+        let canonical_source = std::fs::canonicalize(&source.path).ok()?;
+        let rel = src.strip_prefix(&source.path).ok()?;
+        let file = canonical_source.join(rel);
+        let mut cache = IgnoreCache::default();
+        // The walker stops at the first excluded folder, so check from the top of the source down
+        let items: Vec<&Path> = file.ancestors().take(rel.components().count()).collect();
+        items
+            .iter()
+            .rev()
+            .find_map(|item| cache.excluded_by(item, *item != file, &canonical_source))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::walk;
+    use crate::config::{BackupConfig, SourceConfig, SymlinkPolicy};
+
+    #[test]
+    fn deeper_and_later_ignore_files_take_precedence() {
+        let root = tempfile::tempdir().unwrap();
+        let source = std::fs::canonicalize(root.path()).unwrap().join("docs");
+        for (file, content) in [
+            (".gitignore", "*.log\nbuild/\n"),
+            (".backupignore", "!keep.log\n"),
+            ("a.log", ""),
+            ("keep.log", ""),
+            ("build/out.txt", ""),
+            ("sub/.gitignore", "!sub.log\n"),
+            ("sub/sub.log", ""),
+            ("sub/other.log", ""),
+        ] {
+            std::fs::create_dir_all(source.join(file).parent().unwrap()).unwrap();
+            std::fs::write(source.join(file), content).unwrap();
+        }
+        let mut config = BackupConfig::with_defaults("test");
+        config.sources = vec![SourceConfig::new(source.to_string_lossy().into_owned())];
+        config.use_ignore_files = true;
+        let backupper = Backupper::with_config(config.clone());
+
+        let walker_config = backupper.walker_config(&config.sources[0]);
+        let found = walk::walk(&source, &walker_config, SymlinkPolicy::Skip, &mut |_, _| {}).unwrap();
+        let mut files: Vec<&Path> = found.files.iter().map(|f| f.strip_prefix(&source).unwrap()).collect();
+        files.sort();
+        let expected = [".backupignore", ".gitignore", "keep.log", "sub/.gitignore", "sub/sub.log"];
+        assert_eq!(files, expected.map(Path::new));
+
+        // The log names the ignore file responsible, also for the files of an ignored folder
+        let gitignore = source.join(".gitignore");
+        assert_eq!(backupper.ignored_by(Path::new("docs/a.log")), Some(gitignore.clone()));
+        assert_eq!(backupper.ignored_by(Path::new("docs/build/out.txt")), Some(gitignore.clone()));
+        assert_eq!(backupper.ignored_by(Path::new("docs/sub/other.log")), Some(gitignore));
+        assert_eq!(backupper.ignored_by(Path::new("docs/keep.log")), None);
+        assert_eq!(backupper.ignored_by(Path::new("docs/sub/sub.log")), None);
+    }
+}
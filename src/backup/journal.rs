@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::crypto::Cipher;
+use super::manifest::{manifest_path, ManifestEntry};
+use super::snapshot::Snapshot;
+use super::Backupper;
+use crate::config::OutputFormat;
+
+// Written in a snapshot while it is being backed up, removed once the backup is over: a snapshot
+// still holding it was interrupted (machine turned off, drive pulled) and is resumed by the next run
+pub const JOURNAL_FILENAME: &str = "journal";
+
+// First line of the journal: what the interrupted run was backing up
+#[derive(Serialize, Deserialize)]
+struct JournalHeader {
+    profile: String,
+    format: OutputFormat,
+    // Paths in the snapshot, the sources may be read from another place (a filesystem snapshot) when resuming
+    files: Vec<PathBuf>,
+}
+
+// A line of the journal, encrypted with the snapshot as it holds file names
+fn encode_line(value: &impl Serialize, cipher: Option<&Cipher>) -> Result<String, std::io::Error> {
+    let json = serde_json::to_string(value)?;
+    match cipher {
+        Some(cipher) => {
+            let mut encrypted = Vec::new();
+            cipher.encrypt(json.as_bytes()).read_to_end(&mut encrypted)?;
+            Ok(STANDARD.encode(encrypted))
+        }
+        None => Ok(json),
+    }
+}
+
+fn decode_line<T: DeserializeOwned>(line: &str, cipher: Option<&Cipher>) -> Option<T> {
+    match cipher {
+        Some(cipher) => {
+            let encrypted = STANDARD.decode(line).ok()?;
+            let mut json = Vec::new();
+            cipher.decrypt(&encrypted[..]).read_to_end(&mut json).ok()?;
+            serde_json::from_slice(&json).ok()
+        }
+        None => serde_json::from_str(line).ok(),
+    }
+}
+
+// Journal of the running backup: the files to back up, then the manifest entry of every finished file.
+// Lines are not synced one by one, a file whose line is lost is just backed up again
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    fn create(snapshot: &Snapshot, header: &JournalHeader, cipher: Option<&Cipher>) -> Result<Self, std::io::Error> {
+        let mut file = File::create(snapshot.path.join(JOURNAL_FILENAME))?;
+        writeln!(file, "{}", encode_line(header, cipher)?)?;
+        file.sync_all()?;
+        Ok(Self { file })
+    }
+
+    fn reopen(snapshot: &Snapshot) -> Result<Self, std::io::Error> {
+        let file = OpenOptions::new().append(true).open(snapshot.path.join(JOURNAL_FILENAME))?;
+        Ok(Self { file })
+    }
+
+    // Header and finished files of an interrupted backup; the last line may have been cut short
+    fn read(snapshot: &Snapshot, cipher: Option<&Cipher>) -> Option<(JournalHeader, Vec<ManifestEntry>)> {
+        let mut lines = BufReader::new(File::open(snapshot.path.join(JOURNAL_FILENAME)).ok()?).lines();
+        let header = decode_line(&lines.next()?.ok()?, cipher)?;
+        let done = lines.map_while(Result::ok).filter_map(|line| decode_line(&line, cipher)).collect();
+        Some((header, done))
+    }
+
+    fn record(&mut self, entry: &ManifestEntry, cipher: Option<&Cipher>) -> Result<(), std::io::Error> {
+        writeln!(self.file, "{}", encode_line(entry, cipher)?)
+    }
+}
+
+// Profile that was backing up into a snapshot, from its journal
+pub fn journal_profile(snapshot: &Snapshot, cipher: Option<&Cipher>) -> Option<String> {
+    Journal::read(snapshot, cipher).map(|(header, _)| header.profile)
+}
+
+// Manifest entries of the files an interrupted backup finished, from its journal
+pub fn journal_entries(snapshot: &Snapshot, cipher: Option<&Cipher>) -> Option<Vec<ManifestEntry>> {
+    Journal::read(snapshot, cipher).map(|(_, done)| done)
+}
+
+// Backup interrupted before its end, resumed in the same snapshot
+pub struct Interrupted {
+    pub snapshot: Snapshot,
+    // Every file the interrupted run was backing up
+    pub files: Vec<PathBuf>,
+    // Manifest entries of the files it finished
+    pub done: Vec<ManifestEntry>,
+}
+
+impl Interrupted {
+    // Files left to back up. The finished ones are not looked at again, the others are skipped if they are gone
+    pub fn remaining(&self, backupper: &Backupper) -> Vec<PathBuf> {
+        let done: HashSet<&str> = self.done.iter().map(|e| e.path.as_str()).collect();
+        self.files
+            .iter()
+            .filter(|file| !backupper.rel_path(file).is_ok_and(|rel| done.contains(manifest_path(&rel).as_str())))
+            .filter(|file| file.symlink_metadata().is_ok())
+            .cloned()
+            .collect()
+    }
+}
+
+impl Backupper {
+    // The latest snapshot, if the run writing it was interrupted and can be resumed with the current settings
+    pub(super) fn interrupted_backup(&self, latest: Option<&Snapshot>) -> Option<Interrupted> {
+        let snapshot = latest?;
+        if !snapshot.path.join(JOURNAL_FILENAME).is_file() {
+            return None;
+        }
+        let config = &self.backup_config;
+        let resumable = self.same_encryption(snapshot)
+            .then(|| Journal::read(snapshot, self.cipher()))
+            .flatten()
+            .filter(|(header, _)| header.profile == config.name && header.format == config.output_format)
+            .and_then(|(header, done)| {
+                // Files of a source removed from the configuration can't be found anymore
+                let files = header.files.iter().map(|rel| self.source_path(rel).map(|(_, file)| file));
+                Some((files.collect::<Option<Vec<_>>>()?, done))
+            });
+        match resumable {
+            Some((files, done)) => {
+                println!("Resuming the interrupted backup {}: {} files already done", snapshot.name(), done.len());
+                Some(Interrupted { snapshot: snapshot.clone(), files, done })
+            }
+            None => {
+                println!("The interrupted backup {} can't be resumed with the current settings", snapshot.name());
+                None
+            }
+        }
+    }
+
+    // Archives are written in one go, only folder and repository snapshots are journaled
+    pub(super) fn start_journal(&self, snapshot: &Snapshot, files: &[PathBuf], resumed: bool) {
+        if !matches!(self.backup_config.output_format, OutputFormat::Directory | OutputFormat::Repository) {
+            return;
+        }
+        let journal = match resumed {
+            true => Journal::reopen(snapshot),
+            false => {
+                let header = JournalHeader {
+                    profile: self.backup_config.name.clone(),
+                    format: self.backup_config.output_format,
+                    files: files.iter().filter_map(|file| self.rel_path(file).ok()).collect(),
+                };
+                Journal::create(snapshot, &header, self.cipher())
+            }
+        };
+        match journal {
+            Ok(journal) => *self.journal.lock().unwrap() = Some(journal),
+            Err(e) => eprintln!("Error writing the journal, this backup can't be resumed: {}", e),
+        }
+    }
+
+    pub(super) fn journal_file_done(&self, entry: &ManifestEntry) {
+        if let Some(journal) = self.journal.lock().unwrap().as_mut() {
+            if let Err(e) = journal.record(entry, self.cipher()) {
+                eprintln!("Error writing the journal: {}", e);
+            }
+        }
+    }
+
+    // The backup was cancelled: its journal stays in the snapshot, for the next run to resume it
+    pub(super) fn keep_journal(&self) {
+        self.journal.lock().unwrap().take();
+    }
+
+    // The backup went to its end, or was stopped by an error: there is nothing to resume
+    pub(super) fn finish_journal(&self, snapshot: &Snapshot) {
+        if self.journal.lock().unwrap().take().is_some() {
+            if let Err(e) = std::fs::remove_file(snapshot.path.join(JOURNAL_FILENAME)) {
+                eprintln!("Error removing the journal: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BackupConfig, SourceConfig};
+    use std::path::Path;
+
+    #[test]
+    fn an_interrupted_backup_resumes_with_the_files_it_did_not_finish() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("docs");
+        std::fs::create_dir_all(&source).unwrap();
+        let files: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt"].iter().map(|name| source.join(name)).collect();
+        for file in &files {
+            std::fs::write(file, "content").unwrap();
+        }
+        let mut config = BackupConfig::with_defaults("test");
+        config.sources = vec![SourceConfig::new(source.to_string_lossy().into_owned())];
+        config.destination = root.path().join("destination").to_string_lossy().into_owned();
+        let backupper = Backupper::with_config(config.clone());
+
+        let snapshot = Snapshot::create(Path::new(&config.destination)).unwrap();
+        backupper.start_journal(&snapshot, &files, false);
+        let rel = backupper.rel_path(&files[0]).unwrap();
+        backupper.journal_file_done(&ManifestEntry::new(&rel, &std::fs::metadata(&files[0]).unwrap(), String::new()));
+        backupper.keep_journal();
+        // The machine went off while the next line was being written
+        let mut journal = OpenOptions::new().append(true).open(snapshot.path.join(JOURNAL_FILENAME)).unwrap();
+        write!(journal, "{{\"path\":\"docs/b").unwrap();
+
+        let interrupted = backupper.interrupted_backup(Some(&snapshot)).unwrap();
+        assert_eq!(interrupted.files, files);
+        assert_eq!(interrupted.done.len(), 1);
+        assert_eq!(interrupted.remaining(&backupper), &files[1..]);
+        // A file deleted since is not looked for again
+        std::fs::remove_file(&files[2]).unwrap();
+        assert_eq!(interrupted.remaining(&backupper), &files[1..2]);
+
+        // Another profile or output format starts a new snapshot instead
+        let other = Backupper::with_config(BackupConfig { name: "other".to_string(), ..config.clone() });
+        assert!(other.interrupted_backup(Some(&snapshot)).is_none());
+        let archive = Backupper::with_config(BackupConfig { output_format: OutputFormat::TarZstd, ..config });
+        assert!(archive.interrupted_backup(Some(&snapshot)).is_none());
+
+        backupper.start_journal(&snapshot, &files, true);
+        backupper.finish_journal(&snapshot);
+        assert!(!snapshot.path.join(JOURNAL_FILENAME).exists());
+        assert!(backupper.interrupted_backup(Some(&snapshot)).is_none());
+    }
+}
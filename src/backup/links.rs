@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use super::manifest::{manifest_path, Manifest, ManifestEntry};
+use super::restore::{RestoreOptions, RestoreReport};
+use super::snapshot::Snapshot;
+use super::Backupper;
+use crate::config::SymlinkPolicy;
+
+pub fn create_symlink(target: &Path, link: &Path, is_dir: bool) -> Result<(), std::io::Error> {
+    #[cfg(unix)]
+    {
+        let _ = is_dir;
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        if is_dir {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+}
+
+// Device and inode identifying the content of a file linked from several paths
+#[cfg(unix)]
+fn hard_link_id(meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.is_file() && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_id(_meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+impl Backupper {
+    // Symbolic links are only part of the files to back up when they are stored as links
+    pub(super) fn is_link(&self, file: &Path) -> bool {
+        self.backup_config.symlinks == SymlinkPolicy::CopyAsLink
+            && file.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink())
+    }
+
+    // Metadata of a file to back up: the link itself when links are stored as links, what it points to otherwise
+    pub(super) fn source_metadata(&self, file: &Path) -> Result<std::fs::Metadata, std::io::Error> {
+        match self.backup_config.symlinks {
+            SymlinkPolicy::CopyAsLink => std::fs::symlink_metadata(file),
+            _ => std::fs::metadata(file),
+        }
+    }
+
+    // Manifest entry of a symbolic link, hashed on the path it points to
+    pub(super) fn link_entry(&self, file: &Path) -> Result<ManifestEntry, std::io::Error> {
+        let target = std::fs::read_link(file)?.to_string_lossy().into_owned();
+        let hash = hex::encode(Sha256::digest(target.as_bytes()));
+        let mut entry = ManifestEntry::new(&self.rel_path(file)?, &file.symlink_metadata()?, hash);
+        entry.link = Some(target);
+        Ok(entry)
+    }
+
+    // Recreate a symbolic link in a directory snapshot; an encrypted snapshot only lists it in its
+    // manifest, so that the path it points to is not exposed
+    pub(super) fn copy_link(&self, src: &Path, dst: &Path) -> Result<ManifestEntry, std::io::Error> {
+        if self.cipher().is_none() {
+            Self::create_parent_if_not_exists(dst)?;
+            create_symlink(&std::fs::read_link(src)?, dst, src.is_dir())?;
+            println!("Link copied from {} to {}", src.display(), dst.display());
+        }
+        self.link_entry(src)
+    }
+
+    // Files sharing their content with an earlier file of the list, mapped to the index of that file
+    pub(super) fn hard_link_groups(&self, files: &[PathBuf]) -> HashMap<usize, usize> {
+        let mut first: HashMap<(u64, u64), usize> = HashMap::new();
+        let mut groups = HashMap::new();
+        for (i, file) in files.iter().enumerate() {
+            if let Some(id) = self.source_metadata(file).ok().as_ref().and_then(hard_link_id) {
+                match first.get(&id) {
+                    Some(&primary) => {
+                        groups.insert(i, primary);
+                    }
+                    None => {
+                        first.insert(id, i);
+                    }
+                }
+            }
+        }
+        groups
+    }
+
+    // Link a file to the copy of another path of the same content in the snapshot, None if that copy is missing
+    pub(super) fn copy_hard_link(
+        &self,
+        src: &Path,
+        primary: &Path,
+        snapshot: &Snapshot,
+        entries: &[ManifestEntry],
+    ) -> Option<ManifestEntry> {
+        let primary_rel = self.rel_path(primary).ok()?;
+        let rel = self.rel_path(src).ok()?;
+        let primary_path = manifest_path(&primary_rel);
+        let primary_entry = entries.iter().find(|e| e.path == primary_path)?;
+        let primary_dst = self.stored_path(&snapshot.data_dir(), &primary_rel);
+        let dst = self.stored_path(&snapshot.data_dir(), &rel);
+        let result = Self::create_parent_if_not_exists(&dst)
+            .and_then(|_| std::fs::hard_link(&primary_dst, &dst))
+            .and_then(|_| std::fs::metadata(src));
+        match result {
+            Ok(meta) => {
+                *self.linked_size.lock().unwrap() += meta.len();
+                *self.hard_links.lock().unwrap() += 1;
+                println!("Hard link recreated from {} to {}", primary_dst.display(), dst.display());
+                let mut entry = ManifestEntry::new(&rel, &meta, primary_entry.hash.clone());
+                // Both paths are the same file, with the same metadata
+                entry.metadata = primary_entry.metadata.clone();
+                Some(entry)
+            }
+            Err(e) => {
+                println!("Failed to link {}: {}, copying it instead", dst.display(), e);
+                None
+            }
+        }
+    }
+
+    // Symbolic links are restored from the manifest, whatever the format of the snapshot
+    pub(super) fn restore_links(
+        manifest: &Manifest,
+        options: &RestoreOptions,
+        report: &mut RestoreReport,
+        errors: &mut Vec<std::io::Error>,
+    ) {
+        for entry in &manifest.files {
+            let link = match &entry.link {
+                Some(link) => Path::new(link),
+                None => continue,
+            };
+            let rel = PathBuf::from(&entry.path);
+            if !Self::is_wanted(&rel, options) {
+                continue;
+            }
+            let target = options.target.join(&rel);
+            let restored = (|| {
+                if std::fs::read_link(&target).is_ok_and(|existing| existing == link) {
+                    return Ok(true);
+                }
+                if target.symlink_metadata().is_ok() {
+                    if !options.force {
+                        return Ok(false);
+                    }
+                    std::fs::remove_file(&target)?;
+                }
+                Self::create_parent_if_not_exists(&target)?;
+                let is_dir = target.parent().is_some_and(|parent| parent.join(link).is_dir());
+                create_symlink(link, &target, is_dir)?;
+                println!("Link restored to {}", target.display());
+                Ok(true)
+            })();
+            match restored {
+                Ok(true) => report.restored += 1,
+                Ok(false) => report.skipped_newer.push(target),
+                Err(e) => {
+                    eprintln!("Error restoring link {:?}: {}", rel, e);
+                    errors.push(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::config::{BackupConfig, SourceConfig};
+
+    #[test]
+    fn paths_of_the_same_file_are_linked_to_its_first_copy() {
+        let root = tempfile::tempdir().unwrap();
+        let source = root.path().join("docs");
+        std::fs::create_dir_all(&source).unwrap();
+        let files: Vec<PathBuf> = ["a.txt", "b.txt", "c.txt", "d.txt"].iter().map(|name| source.join(name)).collect();
+        std::fs::write(&files[0], "shared").unwrap();
+        std::fs::hard_link(&files[0], &files[1]).unwrap();
+        std::fs::write(&files[2], "alone").unwrap();
+        std::fs::hard_link(&files[0], &files[3]).unwrap();
+        let mut config = BackupConfig::with_defaults("test");
+        config.sources = vec![SourceConfig::new(source.to_string_lossy().into_owned())];
+        let backupper = Backupper::with_config(config);
+
+        let groups = backupper.hard_link_groups(&files);
+        assert_eq!(groups, HashMap::from([(1, 0), (3, 0)]));
+
+        let snapshot = Snapshot::create(&root.path().join("destination")).unwrap();
+        let primary = snapshot.data_dir().join("docs/a.txt");
+        std::fs::create_dir_all(primary.parent().unwrap()).unwrap();
+        std::fs::write(&primary, "shared").unwrap();
+        let meta = std::fs::metadata(&files[0]).unwrap();
+        let entries = vec![ManifestEntry::new(Path::new("docs/a.txt"), &meta, "hash".to_string())];
+        let entry = backupper.copy_hard_link(&files[1], &files[0], &snapshot, &entries).unwrap();
+        assert_eq!((entry.path.as_str(), entry.hash.as_str()), ("docs/b.txt", "hash"));
+        let linked = std::fs::metadata(snapshot.data_dir().join("docs/b.txt")).unwrap();
+        assert_eq!(hard_link_id(&linked), hard_link_id(&std::fs::metadata(&primary).unwrap()));
+        // Without a copy of the first path, the file is copied on its own
+        assert!(backupper.copy_hard_link(&files[3], &files[2], &snapshot, &entries).is_none());
+    }
+}
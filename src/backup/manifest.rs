@@ -0,0 +1,177 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::crypto::Cipher;
+use super::metadata::FileMetadata;
+use super::snapshot::Snapshot;
+use crate::config::{BackupConfig, DEFAULT_PROFILE_NAME};
+
+// Machine-readable list of the backed up files, stored next to the snapshot data
+pub const MANIFEST_FILENAME: &str = "manifest.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub tool_version: String,
+    pub created_at: String,
+    pub config: BackupConfig,
+    pub files: Vec<ManifestEntry>,
+    // Folders holding the files, deepest first, for their metadata
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub directories: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    // Path relative to the backed up source, always '/' separated
+    pub path: String,
+    pub size: u64,
+    // Modification time of the source file, since the UNIX epoch
+    pub modified_secs: i64,
+    pub modified_nanos: u32,
+    // Access time of the source file, read before the backup reads it
+    #[serde(default)]
+    pub accessed_secs: i64,
+    #[serde(default)]
+    pub accessed_nanos: u32,
+    pub readonly: bool,
+    // Unix permission bits, when the source filesystem has them
+    pub mode: Option<u32>,
+    // SHA-256 of the content, as returned by verify::hash_file
+    pub hash: String,
+    // Chunks of the content in the repository, for snapshots stored in repository mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Vec<String>>,
+    // Path a symbolic link stored as a link points to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    // Owner, access time and extended attributes, when the stored copy doesn't keep them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+}
+
+impl ManifestEntry {
+    // Whether the stored copy lacks the modification time of its source, which modified() still gives
+    pub fn times_lost(&self) -> bool {
+        self.metadata.as_ref().is_some_and(|m| m.times_lost)
+    }
+}
+
+impl Manifest {
+    pub fn new(config: &BackupConfig, files: Vec<ManifestEntry>, directories: Vec<ManifestEntry>) -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            config: config.clone(),
+            files,
+            directories,
+        }
+    }
+
+    // The manifest of an encrypted snapshot is encrypted too, as it lists the file names and hashes
+    pub fn load(snapshot: &Snapshot, cipher: Option<&Cipher>) -> Option<Self> {
+        let file = BufReader::new(File::open(snapshot.path.join(MANIFEST_FILENAME)).ok()?);
+        let manifest = match cipher {
+            Some(cipher) => serde_json::from_reader(cipher.decrypt(file)),
+            None => serde_json::from_reader(file),
+        };
+        match manifest {
+            Ok(manifest) => {
+                let manifest: Self = manifest;
+                // Long encrypted names are stored under a hash, their real name is only known from here
+                if let Some(cipher) = cipher {
+                    let entries = manifest.files.iter().chain(&manifest.directories);
+                    cipher.add_long_names(entries.map(|e| e.path.as_str()));
+                }
+                Some(manifest)
+            }
+            Err(e) => {
+                eprintln!("Error loading manifest of {}: {:?}", snapshot.path.display(), e);
+                None
+            }
+        }
+    }
+
+    // Name of the profile in the configuration of the manifest, without loading its files
+    pub fn load_profile(snapshot: &Snapshot, cipher: Option<&Cipher>) -> Option<String> {
+        #[derive(Deserialize)]
+        struct Profile {
+            name: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct ManifestProfile {
+            config: Profile,
+        }
+        let file = BufReader::new(File::open(snapshot.path.join(MANIFEST_FILENAME)).ok()?);
+        let manifest: ManifestProfile = match cipher {
+            Some(cipher) => serde_json::from_reader(cipher.decrypt(file)).ok()?,
+            None => serde_json::from_reader(file).ok()?,
+        };
+        // Manifests written before profiles existed belong to the default one
+        Some(manifest.config.name.unwrap_or_else(|| DEFAULT_PROFILE_NAME.to_string()))
+    }
+
+    pub fn save(&self, snapshot: &Snapshot, cipher: Option<&Cipher>) -> Result<(), std::io::Error> {
+        let mut file = BufWriter::new(File::create(snapshot.path.join(MANIFEST_FILENAME))?);
+        let data = serde_json::to_vec_pretty(self)?;
+        match cipher {
+            Some(cipher) => {
+                std::io::copy(&mut cipher.encrypt(&data[..]), &mut file)?;
+            }
+            None => file.write_all(&data)?,
+        }
+        file.flush()
+    }
+}
+
+impl ManifestEntry {
+    pub fn new(rel: &Path, meta: &std::fs::Metadata, hash: String) -> Self {
+        let since_epoch = |time: std::io::Result<std::time::SystemTime>| {
+            time.ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or((0, 0), |d| (d.as_secs() as i64, d.subsec_nanos()))
+        };
+        let (modified_secs, modified_nanos) = since_epoch(meta.modified());
+        let (accessed_secs, accessed_nanos) = since_epoch(meta.accessed());
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(meta.permissions().mode())
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Self {
+            path: manifest_path(rel),
+            size: meta.len(),
+            modified_secs,
+            modified_nanos,
+            accessed_secs,
+            accessed_nanos,
+            readonly: meta.permissions().readonly(),
+            mode,
+            hash,
+            chunks: None,
+            link: None,
+            metadata: None,
+        }
+    }
+
+    pub fn modified(&self) -> std::time::SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::new(self.modified_secs.max(0) as u64, self.modified_nanos)
+    }
+
+    pub fn accessed(&self) -> std::time::SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::new(self.accessed_secs.max(0) as u64, self.accessed_nanos)
+    }
+}
+
+// Portable representation of a relative path, independent from the OS separator
+pub fn manifest_path(rel: &Path) -> String {
+    rel.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
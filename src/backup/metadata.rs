@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+use super::atomic;
+use super::manifest::{Manifest, ManifestEntry};
+use super::restore::{RestoreOptions, RestoreReport};
+use super::snapshot::Snapshot;
+use super::{Backupper, CopyOutcome};
+use crate::config::{EncryptionMode, OutputFormat};
+
+// Coarsest modification time a destination may keep (FAT32 stores even seconds only)
+pub const TIME_RESOLUTION: Duration = Duration::from_secs(2);
+// Time that only a filesystem keeping the time to the nanosecond stores as is, unlike FAT32 and exFAT
+const PROBE_TIME: (i64, u32) = (1_000_000_001, 123_456_789);
+
+// Metadata of a file or folder beyond the times and permissions of its manifest entry.
+// It is recorded in the manifest when the stored copy can't keep it, and reapplied on restore
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    // Extended attributes by name, base64 encoded; Linux stores the ACLs as system.posix_acl_* attributes
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+    // The stored copy doesn't have the modification time of its source, only the manifest does
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub times_lost: bool,
+}
+
+#[cfg(target_os = "linux")]
+fn read_xattrs(path: &Path) -> BTreeMap<String, String> {
+    let mut xattrs = BTreeMap::new();
+    // Filesystems without extended attributes just have none
+    if let Ok(names) = xattr::list(path) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(path, &name) {
+                xattrs.insert(name.to_string_lossy().into_owned(), STANDARD.encode(value));
+            }
+        }
+    }
+    xattrs
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_xattrs(_path: &Path) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+#[cfg(target_os = "linux")]
+fn write_xattrs(path: &Path, xattrs: &BTreeMap<String, String>) -> bool {
+    xattrs.iter().fold(true, |written, (name, value)| {
+        let value = STANDARD.decode(value).unwrap_or_default();
+        xattr::set(path, name, &value).is_ok() && written
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_xattrs(_path: &Path, xattrs: &BTreeMap<String, String>) -> bool {
+    xattrs.is_empty()
+}
+
+fn same_time(a: SystemTime, b: SystemTime, resolution: Duration) -> bool {
+    let diff = a.duration_since(b).or_else(|_| b.duration_since(a)).unwrap_or_default();
+    diff.is_zero() || diff < resolution
+}
+
+// Resolution of the modification times kept by the destination, found by giving a file an odd
+// time: none when it is kept exactly, TIME_RESOLUTION when it is rounded (or can't be tried)
+fn time_resolution(destination: &Path) -> Duration {
+    if !destination.is_dir() {
+        return TIME_RESOLUTION;
+    }
+    let probe = FileTime::from_unix_time(PROBE_TIME.0, PROBE_TIME.1);
+    let kept = atomic::temp_file(destination).and_then(|temp| {
+        let time = File::create(&temp)
+            .and_then(|_| filetime::set_file_mtime(&temp, probe))
+            .and_then(|_| std::fs::metadata(&temp)?.modified());
+        let _ = std::fs::remove_file(&temp);
+        time
+    });
+    match kept {
+        Ok(time) if FileTime::from_system_time(time) == probe => Duration::ZERO,
+        _ => TIME_RESOLUTION,
+    }
+}
+
+impl FileMetadata {
+    pub fn read(path: &Path, meta: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let (uid, gid) = {
+            use std::os::unix::fs::MetadataExt;
+            (Some(meta.uid()), Some(meta.gid()))
+        };
+        #[cfg(not(unix))]
+        let (uid, gid) = (None, None);
+
+        Self {
+            uid,
+            gid,
+            xattrs: read_xattrs(path),
+            times_lost: false,
+        }
+    }
+
+    // Give path this metadata and the mode and times of its manifest entry, returning what its
+    // filesystem didn't keep (changing the owner also needs to run as root)
+    pub fn apply(&self, path: &Path, entry: &ManifestEntry) -> Vec<&'static str> {
+        let (mode, modified) = (entry.mode, entry.modified());
+        let mut failed = Vec::new();
+        // Writing user attributes needs write permission: set them before the mode
+        if !write_xattrs(path, &self.xattrs) {
+            failed.push("extended attributes");
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // Changing the owner clears the setuid and setgid bits, so it comes before the mode
+            if let (Some(uid), Some(gid)) = (self.uid, self.gid) {
+                if std::os::unix::fs::chown(path, Some(uid), Some(gid)).is_err() {
+                    failed.push("owner");
+                }
+            }
+            if let Some(mode) = mode {
+                let kept = std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                    .and_then(|_| std::fs::metadata(path))
+                    .is_ok_and(|meta| meta.permissions().mode() & 0o7777 == mode & 0o7777);
+                if !kept {
+                    failed.push("permissions");
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        let kept = filetime::set_file_times(
+            path,
+            FileTime::from_system_time(entry.accessed()),
+            FileTime::from_system_time(modified),
+        )
+        .and_then(|_| std::fs::metadata(path)?.modified())
+        .is_ok_and(|time| time == modified);
+        if !kept {
+            failed.push("timestamps");
+        }
+        failed
+    }
+}
+
+impl Backupper {
+    // Metadata of a source file or folder to keep, None when preservation is off
+    pub(super) fn read_metadata(&self, path: &Path) -> Option<FileMetadata> {
+        if !self.backup_config.preserve_metadata {
+            return None;
+        }
+        std::fs::metadata(path).ok().map(|meta| FileMetadata::read(path, &meta))
+    }
+
+    // Whether two modification times are the same once stored: with metadata preservation the copy
+    // keeps the time of the source, up to the resolution of the destination
+    pub(super) fn same_modified(&self, src: SystemTime, stored: SystemTime) -> bool {
+        match self.backup_config.preserve_metadata {
+            true => {
+                let destination = Path::new(&self.backup_config.destination);
+                same_time(src, stored, *self.time_resolution.get_or_init(|| time_resolution(destination)))
+            }
+            // The copy is as new as the backup that wrote it
+            false => src <= stored,
+        }
+    }
+
+    // Give a copy in a folder snapshot the metadata of its source, returning the metadata if the manifest
+    // has to keep it: the copy is encrypted, or its filesystem couldn't store all of it
+    pub(super) fn store_metadata(
+        &self,
+        metadata: Option<FileMetadata>,
+        entry: &ManifestEntry,
+        dst: &Path,
+    ) -> Option<FileMetadata> {
+        let mut metadata = metadata?;
+        // Encrypted copies are left as written
+        if self.cipher().is_some() {
+            metadata.times_lost = true;
+            return Some(metadata);
+        }
+        let failed = metadata.apply(dst, entry);
+        if failed.is_empty() {
+            return None;
+        }
+        println!("The {} of {} are only kept in the manifest", failed.join(", "), dst.display());
+        metadata.times_lost = failed.contains(&"timestamps");
+        Some(metadata)
+    }
+
+    // Metadata to record for a file of a folder snapshot, once copied or linked
+    pub(super) fn copied_metadata(
+        &self,
+        metadata: Option<FileMetadata>,
+        entry: &ManifestEntry,
+        outcome: CopyOutcome,
+        dst: &Path,
+        previous: Option<&HashMap<String, ManifestEntry>>,
+    ) -> Option<FileMetadata> {
+        match outcome {
+            CopyOutcome::Copied => self.store_metadata(metadata, entry, dst),
+            // The copy is shared with the previous snapshot, whose manifest tells if it kept the metadata
+            CopyOutcome::Linked => {
+                let previous = previous.and_then(|e| e.get(&entry.path));
+                let recorded = previous.is_none_or(|e| e.metadata.is_some());
+                metadata.filter(|_| recorded).map(|mut metadata| {
+                    metadata.times_lost = previous.is_some_and(|e| e.times_lost());
+                    metadata
+                })
+            }
+        }
+    }
+
+    // Manifest entries of the folders holding the backed up files, deepest first. In a folder snapshot
+    // their copies get the metadata of the source folders, once nothing is written into them anymore
+    pub(super) fn directory_entries(&self, files: &[PathBuf], snapshot: &Snapshot) -> Vec<ManifestEntry> {
+        if !self.backup_config.preserve_metadata {
+            return Vec::new();
+        }
+        let mut dirs = BTreeSet::new();
+        for file in files {
+            let source = match self.source_of(file) {
+                Some(source) => Path::new(&source.path),
+                None => continue,
+            };
+            // The folder of each source is the snapshot (or a folder named after the source), left as is
+            for dir in file.ancestors().skip(1).take_while(|dir| *dir != source && dir.starts_with(source)) {
+                if !dirs.insert(dir.to_path_buf()) {
+                    break;
+                }
+            }
+        }
+
+        let mut entries = Vec::new();
+        // Children sort after their parent
+        for dir in dirs.iter().rev() {
+            let (meta, rel) = match std::fs::metadata(dir).and_then(|meta| Ok((meta, self.rel_path(dir)?))) {
+                Ok(found) => found,
+                Err(e) => {
+                    eprintln!("Error reading metadata of {:?}: {}", dir, e);
+                    continue;
+                }
+            };
+            let mut entry = ManifestEntry::new(&rel, &meta, String::new());
+            let metadata = Some(FileMetadata::read(dir, &meta));
+            let dst = self.stored_path(&snapshot.data_dir(), &rel);
+            entry.metadata = match self.backup_config.output_format {
+                OutputFormat::Directory if dst.is_dir() => self.store_metadata(metadata, &entry, &dst),
+                _ => metadata,
+            };
+            entries.push(entry);
+        }
+        entries
+    }
+
+    // Reapply the metadata of the restored files, then of their folders, deepest first
+    pub(super) fn restore_metadata(
+        snapshot: &Snapshot,
+        manifest: &Manifest,
+        options: &RestoreOptions,
+        report: &RestoreReport,
+    ) {
+        let config = &manifest.config;
+        if !config.preserve_metadata {
+            return;
+        }
+        // What the manifest doesn't record was kept by the copies of an unencrypted folder snapshot
+        let stored = (config.output_format == OutputFormat::Directory && config.encryption == EncryptionMode::None)
+            .then(|| snapshot.data_dir());
+        let skipped: HashSet<&PathBuf> = report.skipped_newer.iter().collect();
+
+        let files = manifest.files.iter().filter(|e| e.link.is_none());
+        for entry in files.chain(&manifest.directories) {
+            let rel = PathBuf::from(&entry.path);
+            let target = options.target.join(&rel);
+            if !Self::is_wanted(&rel, options) || skipped.contains(&target) || !target.exists() {
+                continue;
+            }
+            let metadata = entry.metadata.clone().or_else(|| {
+                let copy = stored.as_ref()?.join(&rel);
+                Some(FileMetadata::read(&copy, &std::fs::metadata(&copy).ok()?))
+            });
+            let failed = match metadata {
+                Some(metadata) => metadata.apply(&target, entry),
+                None => continue,
+            };
+            if !failed.is_empty() {
+                println!("The {} of {} could not be restored", failed.join(", "), target.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_compared_up_to_the_resolution() {
+        let time = SystemTime::UNIX_EPOCH + Duration::new(1_000_000_001, 123_456_789);
+        let later = time + Duration::from_millis(1500);
+        assert!(same_time(time, time, Duration::ZERO));
+        assert!(!same_time(time, time + Duration::from_nanos(1), Duration::ZERO));
+        assert!(same_time(time, later, TIME_RESOLUTION));
+        assert!(same_time(later, time, TIME_RESOLUTION));
+        assert!(!same_time(time, time + TIME_RESOLUTION, TIME_RESOLUTION));
+    }
+
+    #[test]
+    fn exact_times_are_found_on_the_destination() {
+        // The temporary folder is on a filesystem keeping nanoseconds (tmpfs, ext4...) on the test machines
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("destination");
+        std::fs::create_dir_all(&destination).unwrap();
+        assert_eq!(time_resolution(&destination), Duration::ZERO);
+        assert_eq!(std::fs::read_dir(destination.join(atomic::TEMP_DIR)).unwrap().count(), 0);
+        std::fs::remove_dir_all(&destination).unwrap();
+        // Nothing is created where there is no destination yet
+        assert_eq!(time_resolution(&destination), TIME_RESOLUTION);
+        assert!(!destination.exists());
+    }
+
+    #[test]
+    fn times_lost_is_only_written_when_set() {
+        let metadata = FileMetadata::default();
+        assert_eq!(serde_json::to_string(&metadata).unwrap(), "{}");
+        let lost = FileMetadata { times_lost: true, ..metadata };
+        let json = serde_json::to_string(&lost).unwrap();
+        assert_eq!(serde_json::from_str::<FileMetadata>(&json).unwrap(), lost);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use rebackup::WalkerRuleResult;
+
+use super::manifest::{manifest_path, ManifestEntry};
+use super::snapshot::Snapshot;
+use super::{atomic, Backupper, BackupperError};
+use crate::config::SyncMode;
+
+// Folder of the destination where files deleted from the source are kept in mirror mode
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+impl Backupper {
+    // Check whether the exclusion rules would skip a file of the snapshot (path as returned by rel_path),
+    // even if it doesn't exist anymore; files of a source removed from the configuration are not excluded
+    pub(super) fn is_excluded(&self, rel: &Path) -> Result<bool, std::io::Error> {
+        let (source, src) = match self.source_path(rel) {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        let walker_config = &self.walker_config(source);
+        let canonical_source = std::fs::canonicalize(&source.path)?;
+        let rel = src.strip_prefix(&source.path).unwrap();
+        let file = canonical_source.join(rel);
+
+        for (i, item) in file.ancestors().take(rel.components().count()).enumerate() {
+            let item_type = if i == 0 {
+                rebackup::WalkerItemType::File
+            } else {
+                rebackup::WalkerItemType::Directory
+            };
+            for rule in &walker_config.rules {
+                let applies_to_type = match rule.only_for {
+                    None => true,
+                    Some(only_type) => only_type == item_type,
+                };
+                if applies_to_type
+                    && (rule.matches)(item, walker_config, &canonical_source)
+                {
+                    if let WalkerRuleResult::ExcludeItem = (rule.action)(item, walker_config, &canonical_source)? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn quarantine_file(&self, prev: &Path, quarantine: &Path) -> Result<(), std::io::Error> {
+        Self::create_parent_if_not_exists(quarantine)?;
+        if std::fs::hard_link(prev, quarantine).is_err() {
+            atomic::copy_file(prev, quarantine, &self.temp_file()?)?;
+        }
+        println!("File deleted from the source moved to {}", quarantine.display());
+        Ok(())
+    }
+
+    // Handle the files of the previous snapshot that are no longer part of the source:
+    // additive mode (and excluded paths) carry them over into the new snapshot, mirror mode drops them
+    pub(super) fn sync_deleted_files(
+        &self,
+        previous: &Snapshot,
+        snapshot: &Snapshot,
+        files: &[PathBuf],
+        previous_entries: Option<&HashMap<String, ManifestEntry>>,
+    ) -> Result<(Vec<ManifestEntry>, Vec<std::io::Error>), BackupperError> {
+        let current: HashSet<PathBuf> = files.iter().filter_map(|f| self.rel_path(f).ok()).collect();
+        let quarantine_root = Path::new(&self.backup_config.destination)
+            .join(QUARANTINE_DIR)
+            .join(snapshot.name());
+
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+
+        for prev in previous.files()? {
+            let stored = prev.strip_prefix(previous.data_dir()).unwrap();
+            let rel = match self.stored_to_source(stored) {
+                Ok(rel) => rel,
+                Err(e) => {
+                    eprintln!("Error handling deleted file {:?}: {}", prev, e);
+                    errors.push(e);
+                    continue;
+                }
+            };
+            if current.contains(&rel) {
+                continue;
+            }
+            let result = self.is_excluded(&rel).and_then(|excluded| {
+                if self.backup_config.sync_mode == SyncMode::Additive || excluded {
+                    let dst = self.stored_path(&snapshot.data_dir(), &rel);
+                    self.link_file(&prev, &prev, &dst)?;
+                    let entry = match previous_entries.and_then(|e| e.get(&manifest_path(&rel))) {
+                        Some(entry) => entry.clone(),
+                        None => ManifestEntry::new(&rel, &std::fs::metadata(&prev)?, self.hash_stored_file(&prev)?),
+                    };
+                    entries.push(entry);
+                } else {
+                    let size = std::fs::metadata(&prev)?.len();
+                    if self.backup_config.quarantine_deleted {
+                        self.quarantine_file(&prev, &quarantine_root.join(stored))?;
+                    } else {
+                        println!("File deleted from the source: {}", rel.display());
+                    }
+                    *self.deleted_size.lock().unwrap() += size;
+                    *self.removed_size.lock().unwrap() += size;
+                }
+                Ok(())
+            });
+            if let Err(e) = result {
+                eprintln!("Error handling deleted file {:?}: {}", prev, e);
+                errors.push(e);
+            }
+        }
+
+        Ok((entries, errors))
+    }
+}
@@ -0,0 +1,196 @@
+use std::path::Path;
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use rebackup::{WalkerItemType, WalkerRule, WalkerRuleResult};
+
+use super::{Backupper, BackupperError};
+use crate::config::SourceConfig;
+
+// Glob characters of a literal extension or directory name
+fn escape(literal: &str) -> String {
+    let mut escaped = String::new();
+    for c in literal.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '{' | '}' | '!' | '#' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Gitignore line equivalent to an entry of the excluded directories: a name matches at any depth,
+// a path ending matches the directories ending with it, an absolute path only that directory of the source
+fn directory_pattern(dir: &str, source: &Path) -> Option<String> {
+    let path = Path::new(dir);
+    if path.is_absolute() {
+        let canonical = std::fs::canonicalize(source).ok()?;
+        let rel = path.strip_prefix(source).or_else(|_| path.strip_prefix(&canonical)).ok()?;
+        if rel.as_os_str().is_empty() {
+            return None;
+        }
+        Some(format!("/{}/", escape(&rel.to_string_lossy().replace('\\', "/"))))
+    } else {
+        Some(format!("**/{}/", escape(&dir.replace('\\', "/"))))
+    }
+}
+
+// Error of every invalid line, so that the configuration window can flag them
+pub fn invalid_patterns(patterns: &[String]) -> Vec<String> {
+    let mut errors = Vec::new();
+    for pattern in patterns.iter().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        if let Err(e) = GitignoreBuilder::new("").add_line(None, pattern) {
+            errors.push(format!("{}: {}", pattern, e));
+        }
+    }
+    errors
+}
+
+// Build a gitignore matcher rooted at the canonical source, the walker hands out canonical paths
+fn build_matcher(root: &Path, lines: impl Iterator<Item = String>) -> Result<Gitignore, ignore::Error> {
+    let mut builder = GitignoreBuilder::new(root);
+    for line in lines {
+        builder.add_line(None, &line)?;
+    }
+    builder.build()
+}
+
+impl Backupper {
+    // Exclusion lines of a source, in increasing precedence: the excluded extensions and directories, then the
+    // global patterns, then the patterns of the source; as in a .gitignore the last matching line wins,
+    // so "!pattern" re-includes what an earlier line excluded
+    fn exclude_lines(&self, source: &SourceConfig) -> Vec<String> {
+        let config = &self.backup_config;
+        let source_path = Path::new(&source.path);
+        let extensions = config.excluded_extensions.iter().chain(&source.excluded_extensions);
+        let directories = config.excluded_directories.iter().chain(&source.excluded_directories);
+        let patterns = config.exclude_patterns.iter().chain(&source.exclude_patterns);
+        extensions
+            .map(|e| e.trim())
+            .filter(|e| !e.is_empty())
+            .map(|e| format!("*.{}", escape(e)))
+            .chain(
+                directories
+                    .map(|d| d.trim())
+                    .filter(|d| !d.is_empty())
+                    .filter_map(|d| directory_pattern(d, source_path)),
+            )
+            .chain(patterns.map(|p| p.trim().to_string()).filter(|p| !p.is_empty()))
+            .collect()
+    }
+
+    // Reject the configuration before walking anything if a pattern can't be parsed
+    pub(super) fn check_patterns(&self) -> Result<(), BackupperError> {
+        let sources = self.backup_config.sources.iter();
+        let patterns = sources.flat_map(|s| &s.exclude_patterns).chain(&self.backup_config.exclude_patterns);
+        let errors = invalid_patterns(&patterns.cloned().collect::<Vec<_>>())
+            .into_iter()
+            .chain(invalid_patterns(&self.backup_config.include_patterns))
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            return Err(BackupperError::BkpError(format!("Invalid patterns: {}", errors.join(", "))));
+        }
+        Ok(())
+    }
+
+    // Walker rules applying the exclude patterns, then the include patterns: when there is any,
+    // only the files they match are backed up (directories are still walked to find them)
+    pub(super) fn pattern_rules(&self, source: &SourceConfig) -> Vec<WalkerRule> {
+        let mut rules = Vec::new();
+        let root = std::fs::canonicalize(&source.path).unwrap_or_else(|_| source.path.clone().into());
+
+        match build_matcher(&root, self.exclude_lines(source).into_iter()) {
+            Ok(excluded) => {
+                for item_type in [WalkerItemType::Directory, WalkerItemType::File, WalkerItemType::Symlink] {
+                    let excluded = excluded.clone();
+                    rules.push(WalkerRule {
+                        name: "exclude patterns",
+                        description: None,
+                        only_for: Some(item_type),
+                        matches: Box::new(move |x, _, _| {
+                            x.starts_with(excluded.path())
+                                && excluded.matched(x, item_type == WalkerItemType::Directory).is_ignore()
+                        }),
+                        action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+                    });
+                }
+            }
+            // Already reported by check_patterns
+            Err(e) => eprintln!("Invalid exclude pattern: {}", e),
+        }
+
+        let include_lines = self.backup_config.include_patterns.iter().map(|p| p.trim().to_string());
+        match build_matcher(&root, include_lines.filter(|p| !p.is_empty())) {
+            Ok(included) if !included.is_empty() => {
+                for item_type in [WalkerItemType::File, WalkerItemType::Symlink] {
+                    let included = included.clone();
+                    rules.push(WalkerRule {
+                        name: "include patterns",
+                        description: None,
+                        only_for: Some(item_type),
+                        matches: Box::new(move |x, _, _| {
+                            x.starts_with(included.path())
+                                && !included.matched_path_or_any_parents(x, false).is_ignore()
+                        }),
+                        action: Box::new(|_, _, _| Ok(WalkerRuleResult::ExcludeItem)),
+                    });
+                }
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Invalid include pattern: {}", e),
+        }
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::walk;
+    use crate::config::{BackupConfig, SymlinkPolicy};
+
+    // Files of the source the walk keeps, relative to it and sorted
+    fn backed_up(backupper: &Backupper, source: &SourceConfig) -> Vec<String> {
+        let root = Path::new(&source.path);
+        let found = walk::walk(root, &backupper.walker_config(source), SymlinkPolicy::Skip, &mut |_, _| {}).unwrap();
+        let mut files: Vec<String> = found
+            .files
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn the_last_matching_line_decides_and_the_source_comes_last() {
+        let root = tempfile::tempdir().unwrap();
+        let path = std::fs::canonicalize(root.path()).unwrap();
+        for file in [
+            "a.log", "keep.log", "x.tmp", "important.tmp", "secret.txt", "b.txt",
+            "cache/c.txt", "sub/cache/d.txt", "sub/e.txt", "notes/n.txt",
+        ] {
+            std::fs::create_dir_all(path.join(file).parent().unwrap()).unwrap();
+            std::fs::write(path.join(file), file).unwrap();
+        }
+        let mut source = SourceConfig::new(path.to_string_lossy().into_owned());
+        source.exclude_patterns = vec!["!important.tmp".to_string(), "notes/".to_string(), "secret.txt".to_string()];
+        let mut config = BackupConfig::with_defaults("test");
+        config.excluded_extensions = vec!["log".to_string()];
+        config.excluded_directories = vec!["cache".to_string()];
+        // A global "!" re-includes what the excluded extensions drop, never what the source itself excludes
+        config.exclude_patterns = vec!["!keep.log".to_string(), "*.tmp".to_string(), "!secret.txt".to_string()];
+        config.sources = vec![source.clone()];
+
+        let backupper = Backupper::with_config(config.clone());
+        assert!(backupper.check_patterns().is_ok());
+        assert_eq!(backed_up(&backupper, &source), ["b.txt", "important.tmp", "keep.log", "sub/e.txt"]);
+
+        // Include patterns then narrow down what the exclusions left
+        config.include_patterns = vec!["*.txt".to_string()];
+        let backupper = Backupper::with_config(config.clone());
+        assert_eq!(backed_up(&backupper, &source), ["b.txt", "sub/e.txt"]);
+
+        config.include_patterns = vec!["[z-a].txt".to_string()];
+        assert!(Backupper::with_config(config).check_patterns().is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use rebackup::WalkerConfig;
+
+use super::manifest::{manifest_path, Manifest, ManifestEntry};
+use super::walk;
+use super::{Backupper, BackupperError};
+use crate::config::{OutputFormat, SyncMode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanAction {
+    New,
+    Changed,
+    Unchanged,
+    Excluded,
+    Deleted,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanEntry {
+    // Path relative to the source
+    pub path: PathBuf,
+    pub action: PlanAction,
+    pub size: u64,
+    // Ignore file that excluded the path, if any
+    pub ignored_by: Option<PathBuf>,
+}
+
+// What a backup would do with the current configuration, computed without writing anything
+#[derive(Debug, Clone, Default)]
+pub struct BackupPlan {
+    pub entries: Vec<PlanEntry>,
+}
+
+impl BackupPlan {
+    // Number of files and bytes for an action
+    pub fn total(&self, action: PlanAction) -> (usize, u64) {
+        self.entries
+            .iter()
+            .filter(|e| e.action == action)
+            .fold((0, 0), |(count, bytes), e| (count + 1, bytes + e.size))
+    }
+}
+
+impl fmt::Display for BackupPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.entries {
+            write!(f, "{:<9} {} ({} bytes)", format!("{:?}", entry.action), entry.path.display(), entry.size)?;
+            match &entry.ignored_by {
+                Some(ignore_file) => writeln!(f, " ignored by {}", ignore_file.display())?,
+                None => writeln!(f)?,
+            }
+        }
+        for action in [
+            PlanAction::New,
+            PlanAction::Changed,
+            PlanAction::Unchanged,
+            PlanAction::Excluded,
+            PlanAction::Deleted,
+        ] {
+            let (count, bytes) = self.total(action);
+            writeln!(f, "{:?}: {} files, {} bytes", action, count, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backupper {
+    fn get_all_source_files(&self) -> Result<Vec<PathBuf>, BackupperError> {
+        let mut walker_config = WalkerConfig::new(Vec::new());
+        walker_config.drop_empty_dirs = true;
+        let mut data = Vec::new();
+        for source in &self.backup_config.sources {
+            let path = PathBuf::from(&source.path);
+            let canonical = std::fs::canonicalize(&path)?;
+            let found = walk::walk(&path, &walker_config, self.backup_config.symlinks, &mut |_, _| {})?;
+            data.extend(found.files.into_iter().map(|x| {
+                match x.strip_prefix(&canonical) {
+                    Ok(rel) => path.join(rel),
+                    Err(_) => x,
+                }
+            }));
+        }
+        Ok(data)
+    }
+
+    // Walk the sources with the same rules as perform_backup and classify every file, without writing anything
+    pub fn plan(&self) -> Result<BackupPlan, BackupperError> {
+        self.check_sources()?;
+        self.check_patterns()?;
+        self.check_filters()?;
+        self.check_encryption()?;
+        // Same rule as perform_backup: a snapshot encrypted differently is never linked to
+        let previous = self
+            .profile_snapshots(Path::new(&self.backup_config.destination))?
+            .pop()
+            .filter(|p| self.same_encryption(p));
+
+        let format = self.backup_config.output_format;
+        let previous_entries: Option<HashMap<String, ManifestEntry>> = previous
+            .as_ref()
+            .filter(|_| matches!(format, OutputFormat::Directory | OutputFormat::Repository))
+            .and_then(|p| Manifest::load(p, self.cipher()))
+            .map(|m| m.files.into_iter().map(|e| (e.path.clone(), e)).collect());
+
+        let files = self.get_target_files()?;
+        let included: HashSet<&Path> = files.iter().map(|f| f.as_path()).collect();
+        let mut plan = BackupPlan::default();
+
+        for file in &files {
+            let src_meta = self.source_metadata(file)?;
+            let rel = self.rel_path(file)?;
+            // Archives are always written in full, so every file counts as new
+            let prev = previous
+                .as_ref()
+                .filter(|_| format == OutputFormat::Directory)
+                .map(|p| self.stored_path(&p.data_dir(), &rel));
+            let action = match prev.as_deref().map(std::fs::metadata) {
+                _ if self.is_link(file) => {
+                    // A link is unchanged while it points to the same path as the stored one
+                    let stored = match &previous_entries {
+                        Some(entries) => entries.get(&manifest_path(&rel)).and_then(|e| e.link.clone()),
+                        None => prev.and_then(|p| std::fs::read_link(p).ok()).map(|t| t.to_string_lossy().into_owned()),
+                    };
+                    match stored {
+                        Some(target) if target == std::fs::read_link(file)?.to_string_lossy() => PlanAction::Unchanged,
+                        Some(_) => PlanAction::Changed,
+                        None => PlanAction::New,
+                    }
+                }
+                _ if format == OutputFormat::Repository => {
+                    Self::repository_action(previous_entries.as_ref(), &rel, &src_meta)?
+                }
+                Some(Ok(pm)) => {
+                    let prev_entry = previous_entries.as_ref().and_then(|e| e.get(&manifest_path(&rel)));
+                    if self.file_changed(&src_meta, &pm, prev_entry)? {
+                        PlanAction::Changed
+                    } else {
+                        PlanAction::Unchanged
+                    }
+                }
+                _ => PlanAction::New,
+            };
+            plan.entries.push(PlanEntry {
+                path: rel,
+                action,
+                size: src_meta.len(),
+                ignored_by: None,
+            });
+        }
+
+        for file in self.get_all_source_files()? {
+            if !included.contains(file.as_path()) {
+                let rel = self.rel_path(&file)?;
+                plan.entries.push(PlanEntry {
+                    ignored_by: self.ignored_by(&rel),
+                    path: rel,
+                    action: PlanAction::Excluded,
+                    size: self.source_metadata(&file)?.len(),
+                });
+            }
+        }
+
+        if let (Some(previous), SyncMode::Mirror) = (&previous, self.backup_config.sync_mode) {
+            let current: HashSet<PathBuf> = self
+                .get_all_source_files()?
+                .into_iter()
+                .map(|f| self.rel_path(&f))
+                .collect::<Result<_, _>>()?;
+            let stored = match &previous_entries {
+                Some(entries) => entries.values().map(|e| (PathBuf::from(&e.path), e.size)).collect(),
+                None => {
+                    let mut stored = Vec::new();
+                    for prev in previous.files()? {
+                        let rel = self.stored_to_source(prev.strip_prefix(previous.data_dir()).unwrap())?;
+                        stored.push((rel, std::fs::metadata(&prev)?.len()));
+                    }
+                    stored
+                }
+            };
+            for (rel, size) in stored {
+                if !current.contains(&rel) && !self.is_excluded(&rel)? {
+                    plan.entries.push(PlanEntry {
+                        size,
+                        path: rel,
+                        action: PlanAction::Deleted,
+                        ignored_by: None,
+                    });
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use super::Backupper;
+use crate::config::FileOrder;
+
+// Group of files backed up before the next one
+#[derive(Debug, Clone)]
+pub struct PriorityTier {
+    pub name: String,
+    pub files: usize,
+    // Files backed up so far, the tier is complete once it reaches files
+    pub done: usize,
+}
+
+impl PriorityTier {
+    pub fn is_complete(&self) -> bool {
+        self.done == self.files
+    }
+}
+
+// Tiers of the running backup, updated by the copy workers
+#[derive(Default)]
+pub(super) struct TierTracker {
+    tiers: Vec<PriorityTier>,
+    by_file: HashMap<PathBuf, usize>,
+}
+
+impl Backupper {
+    fn priority_paths(&self) -> impl Iterator<Item = &str> {
+        self.backup_config.priority_paths.iter().map(|p| p.trim().trim_matches(['/', '\\'])).filter(|p| !p.is_empty())
+    }
+
+    fn priority_extensions(&self) -> impl Iterator<Item = &str> {
+        self.backup_config.priority_extensions.iter().map(|e| e.trim().trim_start_matches('.')).filter(|e| !e.is_empty())
+    }
+
+    // Whether any priority tier is configured besides the other files
+    pub fn has_priorities(&self) -> bool {
+        self.priority_paths().next().is_some() || self.priority_extensions().next().is_some()
+    }
+
+    // Names of the tiers, most important first
+    fn tier_names(&self) -> Vec<String> {
+        self.priority_paths()
+            .map(|p| format!("path {}", p))
+            .chain(self.priority_extensions().map(|e| format!("extension {}", e)))
+            .chain(std::iter::once("other files".to_string()))
+            .collect()
+    }
+
+    // Index of the first tier matching a file of the source
+    fn tier_of(&self, file: &Path) -> usize {
+        // A file outside of the sources fails to copy anyway, no path matches it
+        let rel = self.rel_path(file).unwrap_or_default();
+        let paths = self.priority_paths().count();
+        if let Some(i) = self.priority_paths().position(|p| rel.starts_with(p)) {
+            return i;
+        }
+        let extension = file.extension().and_then(OsStr::to_str).unwrap_or("");
+        match self.priority_extensions().position(|e| e.eq_ignore_ascii_case(extension)) {
+            Some(i) => paths + i,
+            None => paths + self.priority_extensions().count(),
+        }
+    }
+
+    // Put the most important files first, so that they reach the destination even if the backup is interrupted
+    pub(super) fn sort_by_priority(&self, files: &mut [PathBuf]) {
+        let order = self.backup_config.priority_order;
+        if !self.has_priorities() && order == FileOrder::Walk {
+            return;
+        }
+        files.sort_by_cached_key(|file| {
+            let meta = std::fs::metadata(file).ok();
+            let key = match order {
+                FileOrder::Walk => 0,
+                FileOrder::NewestFirst => meta
+                    .and_then(|m| m.modified().ok())
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map_or(0, |d| -(d.as_nanos() as i128)),
+                FileOrder::SmallestFirst => meta.map_or(0, |m| m.len() as i128),
+            };
+            (self.tier_of(file), key)
+        });
+    }
+
+    // Indices of the files of the list in each tier, most important tier first
+    pub(super) fn files_by_tier(&self, files: &[PathBuf]) -> Vec<Vec<usize>> {
+        let mut tiers = vec![Vec::new(); self.tier_names().len()];
+        for (i, file) in files.iter().enumerate() {
+            tiers[self.tier_of(file)].push(i);
+        }
+        tiers
+    }
+
+    pub(super) fn start_tiers(&self, files: &[PathBuf]) {
+        let mut tiers: Vec<PriorityTier> = self
+            .tier_names()
+            .into_iter()
+            .map(|name| PriorityTier { name, files: 0, done: 0 })
+            .collect();
+        let mut by_file = HashMap::new();
+        for file in files {
+            let tier = self.tier_of(file);
+            tiers[tier].files += 1;
+            by_file.insert(file.clone(), tier);
+        }
+        *self.tiers.lock().unwrap() = TierTracker { tiers, by_file };
+    }
+
+    pub(super) fn tier_file_done(&self, file: &Path) {
+        let mut tracker = self.tiers.lock().unwrap();
+        if let Some(&tier) = tracker.by_file.get(file) {
+            tracker.tiers[tier].done += 1;
+        }
+    }
+
+    // Tiers of the last backup holding at least a file, most important first
+    pub fn priority_tiers(&self) -> Vec<PriorityTier> {
+        self.tiers.lock().unwrap().tiers.iter().filter(|t| t.files > 0).cloned().collect()
+    }
+}
@@ -0,0 +1,135 @@
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use super::Backupper;
+
+#[derive(Debug, Clone)]
+pub enum ProgressEvent {
+    // The sources are being walked: files found so far and the folder being listed
+    Scanning { files: usize, folder: String },
+    // The source has been walked: number of files to back up and their total size
+    Scanned { files: usize, bytes: u64 },
+    Progress(BackupProgress),
+    Finished(BackupOutcome),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupOutcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BackupProgress {
+    pub files_done: usize,
+    pub files_total: usize,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    pub current_file: String,
+    pub eta: Option<Duration>,
+}
+
+impl BackupProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.bytes_total == 0 {
+            if self.files_total == 0 {
+                return 1.0;
+            }
+            return self.files_done as f32 / self.files_total as f32;
+        }
+        self.bytes_done as f32 / self.bytes_total as f32
+    }
+}
+
+// Progress of the running backup, shared by the copy workers
+pub(super) struct ProgressState {
+    progress: BackupProgress,
+    started: Instant,
+}
+
+impl ProgressState {
+    pub(super) fn new() -> Self {
+        Self {
+            progress: BackupProgress::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Backupper {
+    pub fn set_progress_sender(&mut self, sender: Sender<ProgressEvent>) {
+        self.progress_sender = Some(sender);
+    }
+
+    pub(super) fn send_progress(&self, event: ProgressEvent) {
+        if let Some(sender) = &self.progress_sender {
+            // Nobody listening anymore is not a reason to stop the backup
+            let _ = sender.send(event);
+        }
+    }
+
+    pub fn current_progress(&self) -> BackupProgress {
+        self.progress.lock().unwrap().progress.clone()
+    }
+
+    pub(super) fn start_progress(&self, files: &[std::path::PathBuf]) {
+        let bytes = files
+            .iter()
+            .filter_map(|f| std::fs::metadata(f).ok())
+            .map(|m| m.len())
+            .sum();
+        *self.progress.lock().unwrap() = ProgressState {
+            progress: BackupProgress {
+                files_total: files.len(),
+                bytes_total: bytes,
+                ..Default::default()
+            },
+            started: Instant::now(),
+        };
+        self.send_progress(ProgressEvent::Scanned {
+            files: files.len(),
+            bytes,
+        });
+    }
+
+    pub(super) fn scanning(&self, files: usize, folder: &Path) {
+        self.send_progress(ProgressEvent::Scanning {
+            files,
+            folder: folder.display().to_string(),
+        });
+    }
+
+    // A worker picked the file: it is the one shown until another one starts
+    pub(super) fn file_started(&self, file: &Path) {
+        let progress = {
+            let mut state = self.progress.lock().unwrap();
+            state.progress.current_file = file.display().to_string();
+            state.progress.clone()
+        };
+        self.send_progress(ProgressEvent::Progress(progress));
+    }
+
+    // A file was processed, backed_up is false when it failed: its tier is then not complete
+    pub(super) fn file_done(&self, file: &Path, backed_up: bool) {
+        if backed_up {
+            self.tier_file_done(file);
+        }
+        let size = std::fs::metadata(file).map_or(0, |m| m.len());
+        let progress = {
+            let mut state = self.progress.lock().unwrap();
+            let elapsed = state.started.elapsed();
+            let p = &mut state.progress;
+            p.files_done += 1;
+            p.bytes_done += size;
+            p.eta = if p.bytes_done > 0 && p.bytes_total >= p.bytes_done {
+                Some(elapsed.mul_f64((p.bytes_total - p.bytes_done) as f64 / p.bytes_done as f64))
+            } else {
+                None
+            };
+            p.clone()
+        };
+        self.send_progress(ProgressEvent::Progress(progress));
+    }
+}
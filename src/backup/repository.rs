@@ -0,0 +1,300 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use fastcdc::v2020::StreamCDC;
+use sha2::{Digest, Sha256};
+
+use super::crypto::Cipher;
+use super::manifest::{manifest_path, ManifestEntry};
+use super::plan::PlanAction;
+use super::snapshot::Snapshot;
+use super::{atomic, Backupper};
+use crate::config::SyncMode;
+
+// Folder of the destination holding the chunks shared by all the snapshots of the repository
+pub const CHUNKS_DIR: &str = "chunks";
+// Small enough to isolate a changed block of a large file, large enough to keep the number of chunk files low
+const MIN_CHUNK_SIZE: u32 = 64 * 1024;
+const AVG_CHUNK_SIZE: u32 = 256 * 1024;
+const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+const CHUNK_COMPRESSION_LEVEL: i32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ChunkState {
+    Writing,
+    Stored,
+}
+
+// Content-defined chunks addressed by hash; the manifest of each snapshot lists the chunks of every file
+pub struct Repository<'a> {
+    destination: PathBuf,
+    path: PathBuf,
+    cipher: Option<&'a Cipher>,
+    // Chunks already stored or being stored, so that two workers never write the same one
+    known: Mutex<HashMap<String, ChunkState>>,
+    // Notified whenever a chunk being written is done
+    written: Condvar,
+}
+
+impl<'a> Repository<'a> {
+    pub fn open(destination: &Path, cipher: Option<&'a Cipher>) -> Self {
+        Self {
+            destination: destination.to_path_buf(),
+            path: destination.join(CHUNKS_DIR),
+            cipher,
+            known: Mutex::new(HashMap::new()),
+            written: Condvar::new(),
+        }
+    }
+
+    fn chunk_path(&self, id: &str) -> PathBuf {
+        self.path.join(&id[..2]).join(id)
+    }
+
+    // With encryption the id is keyed, so that chunk names don't tell which content is stored
+    fn chunk_id(&self, data: &[u8]) -> String {
+        match self.cipher {
+            Some(cipher) => cipher.chunk_id(data),
+            None => hex::encode(Sha256::digest(data)),
+        }
+    }
+
+    // Store a chunk unless the repository already has it, returning whether it was written.
+    // Once it returns the chunk is on the destination, even when another worker wrote it
+    fn store_chunk(&self, id: &str, data: &[u8]) -> Result<bool, std::io::Error> {
+        let path = self.chunk_path(id);
+        let mut known = self.known.lock().unwrap();
+        while let Some(state) = known.get(id) {
+            if *state == ChunkState::Stored {
+                return Ok(false);
+            }
+            known = self.written.wait(known).unwrap();
+        }
+        if path.exists() {
+            known.insert(id.to_string(), ChunkState::Stored);
+            return Ok(false);
+        }
+        known.insert(id.to_string(), ChunkState::Writing);
+        drop(known);
+
+        let result = (|| {
+            Backupper::create_parent_if_not_exists(&path)?;
+            let compressed = zstd::encode_all(data, CHUNK_COMPRESSION_LEVEL)?;
+            let temp = atomic::temp_file(&self.destination)?;
+            match self.cipher {
+                Some(cipher) => atomic::write_file(&mut cipher.encrypt(&compressed[..]), &path, &temp, None),
+                None => atomic::write_file(&mut &compressed[..], &path, &temp, None),
+            }
+        })();
+        let mut known = self.known.lock().unwrap();
+        match &result {
+            Ok(_) => known.insert(id.to_string(), ChunkState::Stored),
+            // Let another file retry it
+            Err(_) => known.remove(id),
+        };
+        self.written.notify_all();
+        result.map(|_| true)
+    }
+
+    fn read_chunk(&self, id: &str) -> Result<Vec<u8>, std::io::Error> {
+        let file = BufReader::new(File::open(self.chunk_path(id))?);
+        let data = match self.cipher {
+            Some(cipher) => zstd::decode_all(cipher.decrypt(file))?,
+            None => zstd::decode_all(file)?,
+        };
+        if self.chunk_id(&data) != id {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk {} is corrupted", id),
+            ));
+        }
+        Ok(data)
+    }
+
+    // Write the content of a file from its chunks, returning its SHA-256
+    pub fn read_file(&self, chunks: &[String], writer: &mut dyn Write) -> Result<String, std::io::Error> {
+        let mut hasher = Sha256::new();
+        for id in chunks {
+            let data = self.read_chunk(id)?;
+            hasher.update(&data);
+            writer.write_all(&data)?;
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+
+    // Delete every stored chunk missing from referenced, returning the bytes freed
+    pub fn remove_unreferenced(&self, referenced: &HashSet<String>) -> Result<u64, std::io::Error> {
+        let mut freed = 0;
+        if !self.path.is_dir() {
+            return Ok(freed);
+        }
+        for dir in std::fs::read_dir(&self.path)? {
+            let dir = dir?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for chunk in std::fs::read_dir(&dir)? {
+                let chunk = chunk?;
+                let id = chunk.file_name().to_string_lossy().into_owned();
+                if !referenced.contains(&id) {
+                    freed += chunk.metadata()?.len();
+                    std::fs::remove_file(chunk.path())?;
+                    println!("Unused chunk {} removed", id);
+                }
+            }
+        }
+        Ok(freed)
+    }
+}
+
+impl Backupper {
+    // Split a file into chunks and store the ones the repository doesn't have yet
+    fn store_file(&self, repository: &Repository, src: &Path) -> Result<ManifestEntry, std::io::Error> {
+        let file = File::open(src)?;
+        let meta = file.metadata()?;
+        let mut hasher = Sha256::new();
+        let mut chunks = Vec::new();
+        let chunker = StreamCDC::new(
+            BufReader::new(file).take(meta.len()),
+            MIN_CHUNK_SIZE,
+            AVG_CHUNK_SIZE,
+            MAX_CHUNK_SIZE,
+        );
+        for chunk in chunker {
+            let chunk = chunk?;
+            hasher.update(&chunk.data);
+            let id = repository.chunk_id(&chunk.data);
+            if repository.store_chunk(&id, &chunk.data)? {
+                *self.added_size.lock().unwrap() += chunk.length as u64;
+            } else {
+                *self.linked_size.lock().unwrap() += chunk.length as u64;
+            }
+            chunks.push(id);
+        }
+        println!("File stored from {} in {} chunks", src.display(), chunks.len());
+
+        let mut entry = ManifestEntry::new(&self.rel_path(src)?, &meta, hex::encode(hasher.finalize()));
+        entry.chunks = Some(chunks);
+        Ok(entry)
+    }
+
+    // Every file is read and chunked, only the chunks missing from the repository are written
+    pub(super) fn store_files(&self, files: &[PathBuf], snapshot: &Snapshot) -> (Vec<ManifestEntry>, Vec<std::io::Error>) {
+        let repository = Repository::open(Path::new(&self.backup_config.destination), self.cipher());
+        self.run_workers(files, |file| {
+            if self.is_link(file) {
+                return self.link_entry(file);
+            }
+            let metadata = self.read_metadata(file);
+            let mut entry = self.store_file(&repository, file)?;
+            entry.metadata = metadata;
+            self.copied_files.lock().unwrap().push((file.clone(), snapshot.path.join(self.rel_path(file)?)));
+            Ok(entry)
+        })
+    }
+
+    // Files of the previous snapshot missing from the source: additive mode (and excluded paths)
+    // keep referencing their chunks, mirror mode drops them from the new snapshot
+    pub(super) fn sync_deleted_entries(
+        &self,
+        files: &[PathBuf],
+        previous_entries: Option<&HashMap<String, ManifestEntry>>,
+    ) -> (Vec<ManifestEntry>, Vec<std::io::Error>) {
+        let current: HashSet<String> = files.iter().filter_map(|f| self.rel_path(f).ok()).map(|rel| manifest_path(&rel)).collect();
+        let mut entries = Vec::new();
+        let mut errors = Vec::new();
+
+        let previous = previous_entries.into_iter().flat_map(|e| e.values());
+        // Entries of a non-repository snapshot have no chunks to reference
+        for entry in previous.filter(|e| e.chunks.is_some() && !current.contains(&e.path)) {
+            match self.is_excluded(Path::new(&entry.path)) {
+                Ok(excluded) if self.backup_config.sync_mode == SyncMode::Additive || excluded => {
+                    entries.push(entry.clone());
+                }
+                Ok(_) => {
+                    // Still available from the older snapshots until their chunks are pruned
+                    println!("File deleted from the source: {}", entry.path);
+                    *self.deleted_size.lock().unwrap() += entry.size;
+                    *self.removed_size.lock().unwrap() += entry.size;
+                }
+                Err(e) => {
+                    eprintln!("Error handling deleted file {:?}: {}", entry.path, e);
+                    errors.push(e);
+                }
+            }
+        }
+        (entries, errors)
+    }
+
+    // Plan action of a file in repository mode, guessed from the previous manifest without reading the file
+    pub(super) fn repository_action(
+        previous_entries: Option<&HashMap<String, ManifestEntry>>,
+        rel: &Path,
+        src_meta: &std::fs::Metadata,
+    ) -> Result<PlanAction, std::io::Error> {
+        match previous_entries.and_then(|e| e.get(&manifest_path(rel))) {
+            Some(entry) if entry.chunks.is_some() => {
+                if entry.size == src_meta.len() && entry.modified() == src_meta.modified()? {
+                    Ok(PlanAction::Unchanged)
+                } else {
+                    Ok(PlanAction::Changed)
+                }
+            }
+            _ => Ok(PlanAction::New),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_chunk_is_written_once_and_present_for_every_caller() {
+        let destination = tempfile::tempdir().unwrap();
+        let repository = Repository::open(destination.path(), None);
+        let data = vec![42u8; 512 * 1024];
+        let id = repository.chunk_id(&data);
+        let written: Vec<bool> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let written = repository.store_chunk(&id, &data).unwrap();
+                        assert!(repository.chunk_path(&id).is_file());
+                        written
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        assert_eq!(written.iter().filter(|w| **w).count(), 1);
+    }
+
+    #[test]
+    fn files_are_rebuilt_from_their_chunks() {
+        let destination = tempfile::tempdir().unwrap();
+        let repository = Repository::open(destination.path(), None);
+        let (first, second) = (b"first chunk".to_vec(), b"second chunk".to_vec());
+        let ids = vec![repository.chunk_id(&first), repository.chunk_id(&second)];
+        assert!(repository.store_chunk(&ids[0], &first).unwrap());
+        assert!(repository.store_chunk(&ids[1], &second).unwrap());
+        assert!(!Repository::open(destination.path(), None).store_chunk(&ids[0], &first).unwrap());
+
+        let mut content = Vec::new();
+        let hash = repository.read_file(&ids, &mut content).unwrap();
+        assert_eq!(content, b"first chunksecond chunk");
+        assert_eq!(hash, hex::encode(Sha256::digest(&content)));
+
+        // A chunk whose content doesn't match its id is reported
+        std::fs::write(repository.chunk_path(&ids[1]), zstd::encode_all(&first[..], 0).unwrap()).unwrap();
+        assert!(repository.read_file(&ids, &mut std::io::sink()).is_err());
+
+        let referenced = HashSet::from([ids[0].clone()]);
+        assert!(repository.remove_unreferenced(&referenced).unwrap() > 0);
+        assert!(repository.chunk_path(&ids[0]).is_file());
+        assert!(!repository.chunk_path(&ids[1]).exists());
+    }
+}
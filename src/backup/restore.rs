@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::archive;
+use super::atomic;
+use super::crypto::Cipher;
+use super::manifest::{manifest_path, Manifest};
+use super::repository::Repository;
+use super::snapshot::Snapshot;
+use super::{Backupper, BackupperError};
+use crate::config::OutputFormat;
+
+pub struct RestoreOptions {
+    // Destination folder of the backups, or a single snapshot inside it
+    pub backup: PathBuf,
+    pub target: PathBuf,
+    // Only restore the files whose path (relative to the backed up source) starts with this prefix
+    pub prefix: Option<PathBuf>,
+    // Overwrite files of the target that are newer than the backed up copy
+    pub force: bool,
+    // Key file of an encrypted backup, when it differs from the configured one
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    pub restored: usize,
+    pub skipped_newer: Vec<PathBuf>,
+}
+
+impl Backupper {
+    fn find_snapshot(backup: &Path) -> Result<Snapshot, BackupperError> {
+        match Snapshot::open(backup) {
+            Some(snapshot) => Ok(snapshot),
+            None => Snapshot::latest(backup).ok_or_else(|| {
+                BackupperError::BkpError(format!("No backup found in {}", backup.display()))
+            }),
+        }
+    }
+
+    fn target_is_newer(target: &Path, backup_time: SystemTime) -> Result<bool, std::io::Error> {
+        if let Ok(target_meta) = std::fs::metadata(target) {
+            if target_meta.modified()? > backup_time {
+                println!("File newer than the backup, not restored: {}", target.display());
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn restore_file(dst: &Path, target: &Path, force: bool, cipher: Option<&Cipher>) -> Result<bool, std::io::Error> {
+        if !force && Self::target_is_newer(target, std::fs::metadata(dst)?.modified()?)? {
+            return Ok(false);
+        }
+        Self::create_parent_if_not_exists(target)?;
+        // Written through a temporary file, so that a copy failing to decrypt never replaces the target
+        let temp = atomic::temp_path(target);
+        match cipher {
+            Some(cipher) => {
+                let reader = File::open(dst)?;
+                let permissions = reader.metadata()?.permissions();
+                atomic::write_file(&mut cipher.decrypt(BufReader::new(reader)), target, &temp, Some(permissions))?;
+            }
+            None => {
+                atomic::copy_file(dst, target, &temp)?;
+            }
+        }
+        println!("File restored from {} to {}", dst.display(), target.display());
+        Ok(true)
+    }
+
+    pub fn restore(&self, options: &RestoreOptions) -> Result<RestoreReport, BackupperError> {
+        let snapshot = Self::find_snapshot(&options.backup)?;
+        println!("Restoring snapshot {} into {}", snapshot.path.display(), options.target.display());
+
+        let key_file = match &options.key_file {
+            Some(key_file) => key_file.to_string_lossy().to_string(),
+            None => self.backup_config.key_file.clone(),
+        };
+        let cipher = Cipher::for_snapshot(&snapshot, &key_file, &self.backup_config.passphrase_file)
+            .map_err(BackupperError::BkpError)?;
+
+        let mut report = RestoreReport::default();
+        let mut errors = Vec::new();
+
+        if let Some(archive) = snapshot.archive() {
+            self.restore_archive(&snapshot, &archive, cipher.as_ref(), options, &mut report, &mut errors);
+        }
+        let manifest = Manifest::load(&snapshot, cipher.as_ref());
+        if let Some(manifest) = &manifest {
+            if manifest.config.output_format == OutputFormat::Repository {
+                Self::restore_repository(&snapshot, manifest, cipher.as_ref(), options, &mut report, &mut errors);
+            }
+        }
+        for file in snapshot.files()? {
+            let rel = match Self::create_src_path(&snapshot.data_dir(), &file, cipher.as_ref()) {
+                Ok(rel) => rel,
+                Err(e) => {
+                    eprintln!("Error restoring file {:?}: {}", file, e);
+                    errors.push(e);
+                    continue;
+                }
+            };
+            if !Self::is_wanted(&rel, options) {
+                continue;
+            }
+            let target = options.target.join(&rel);
+            match Self::restore_file(&file, &target, options.force, cipher.as_ref()) {
+                Ok(true) => report.restored += 1,
+                Ok(false) => report.skipped_newer.push(target),
+                Err(e) => {
+                    eprintln!("Error restoring file {:?}: {}", file, e);
+                    errors.push(e);
+                }
+            }
+        }
+        if let Some(manifest) = &manifest {
+            Self::restore_links(manifest, options, &mut report, &mut errors);
+            Self::restore_metadata(&snapshot, manifest, options, &report);
+        }
+        if !errors.is_empty() {
+            return Err(BackupperError::IoError(errors));
+        }
+
+        Ok(report)
+    }
+
+    // Whether a file stored under rel is to be restored
+    pub(super) fn is_wanted(rel: &Path, options: &RestoreOptions) -> bool {
+        // Never write outside of the target, whatever the backup contains
+        if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+            return false;
+        }
+        match &options.prefix {
+            Some(prefix) => rel.starts_with(prefix),
+            None => true,
+        }
+    }
+
+    fn restore_repository(
+        snapshot: &Snapshot,
+        manifest: &Manifest,
+        cipher: Option<&Cipher>,
+        options: &RestoreOptions,
+        report: &mut RestoreReport,
+        errors: &mut Vec<std::io::Error>,
+    ) {
+        let repository = Repository::open(snapshot.path.parent().unwrap(), cipher);
+        for entry in &manifest.files {
+            let (rel, chunks) = match &entry.chunks {
+                Some(chunks) => (PathBuf::from(&entry.path), chunks),
+                None => continue,
+            };
+            if !Self::is_wanted(&rel, options) {
+                continue;
+            }
+            let target = options.target.join(&rel);
+            let restored = Self::target_is_newer(&target, entry.modified()).and_then(|newer| {
+                if newer && !options.force {
+                    return Ok(false);
+                }
+                Self::create_parent_if_not_exists(&target)?;
+                // Only moved over the target once its content matches the backup
+                let temp = atomic::temp_path(&target);
+                let written = File::create(&temp).and_then(|mut file| {
+                    let hash = repository.read_file(chunks, &mut file)?;
+                    if hash != entry.hash {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Restored content of {} doesn't match the backup", entry.path),
+                        ));
+                    }
+                    file.sync_all()
+                });
+                if let Err(e) = written {
+                    let _ = std::fs::remove_file(&temp);
+                    return Err(e);
+                }
+                atomic::persist(&temp, &target)?;
+                println!("File restored from {} chunks to {}", chunks.len(), target.display());
+                Ok(true)
+            });
+            match restored {
+                Ok(true) => report.restored += 1,
+                Ok(false) => report.skipped_newer.push(target),
+                Err(e) => {
+                    eprintln!("Error restoring file {:?}: {}", rel, e);
+                    errors.push(e);
+                }
+            }
+        }
+    }
+
+    fn restore_archive(
+        &self,
+        snapshot: &Snapshot,
+        archive: &Path,
+        cipher: Option<&Cipher>,
+        options: &RestoreOptions,
+        report: &mut RestoreReport,
+        errors: &mut Vec<std::io::Error>,
+    ) {
+        // Archive entries carry no reliable time: compare with the source time recorded in the manifest
+        let times: Option<HashMap<String, SystemTime>> =
+            Manifest::load(snapshot, cipher).map(|m| m.files.iter().map(|e| (e.path.clone(), e.modified())).collect());
+
+        let result = archive::for_each_file(archive, |stored, content| {
+            let rel = match cipher.map(|c| c.decrypt_path(stored)).transpose() {
+                Ok(rel) => rel.unwrap_or_else(|| stored.to_path_buf()),
+                Err(e) => {
+                    eprintln!("Error restoring file {:?}: {}", stored, e);
+                    errors.push(e);
+                    return Ok(());
+                }
+            };
+            if !Self::is_wanted(&rel, options) {
+                return Ok(());
+            }
+            let target = options.target.join(&rel);
+            let backup_time = match times.as_ref().map(|times| times.get(&manifest_path(&rel))) {
+                Some(Some(time)) => *time,
+                // Stored padded with zeros, the file couldn't be read to its end
+                Some(None) => {
+                    println!("File not fully backed up, not restored: {}", target.display());
+                    return Ok(());
+                }
+                None => UNIX_EPOCH,
+            };
+            let restored = Self::target_is_newer(&target, backup_time).and_then(|newer| {
+                if newer && !options.force {
+                    return Ok(false);
+                }
+                Self::create_parent_if_not_exists(&target)?;
+                let temp = atomic::temp_path(&target);
+                match cipher {
+                    Some(cipher) => atomic::write_file(&mut cipher.decrypt(content), &target, &temp, None)?,
+                    None => atomic::write_file(content, &target, &temp, None)?,
+                };
+                println!("File restored from {} to {}", archive.join(stored).display(), target.display());
+                Ok(true)
+            });
+            match restored {
+                Ok(true) => report.restored += 1,
+                Ok(false) => report.skipped_newer.push(target),
+                Err(e) => {
+                    eprintln!("Error restoring file {:?}: {}", rel, e);
+                    errors.push(e);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            eprintln!("Error reading archive {:?}: {}", archive, e);
+            errors.push(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn restore_keeps_newer_files_and_honours_the_prefix() {
+        let root = tempfile::tempdir().unwrap();
+        let snapshot = Snapshot::create(&root.path().join("destination")).unwrap();
+        for (rel, content) in [("docs/a.txt", "backed up a"), ("docs/sub/b.txt", "backed up b"), ("music/c.txt", "c")] {
+            let file = snapshot.data_dir().join(rel);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, content).unwrap();
+        }
+        let target = root.path().join("target");
+        let backupper = Backupper::new();
+        let options = |prefix: Option<&str>, force| RestoreOptions {
+            backup: snapshot.path.clone(),
+            target: target.clone(),
+            prefix: prefix.map(PathBuf::from),
+            force,
+            key_file: None,
+        };
+
+        let report = backupper.restore(&options(Some("docs/sub"), false)).unwrap();
+        assert_eq!(report.restored, 1);
+        assert_eq!(std::fs::read_to_string(target.join("docs/sub/b.txt")).unwrap(), "backed up b");
+        assert!(!target.join("docs/a.txt").exists() && !target.join("music").exists());
+        // "docs/s" is not a folder of the backup: the prefix matches whole path components
+        assert_eq!(backupper.restore(&options(Some("docs/s"), false)).unwrap().restored, 0);
+
+        // A file changed after the backup is kept, unless the restore is forced
+        let newer = target.join("docs/a.txt");
+        std::fs::write(&newer, "changed since").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        std::fs::File::options().write(true).open(&newer).unwrap().set_modified(later).unwrap();
+        let report = backupper.restore(&options(Some("docs/a.txt"), false)).unwrap();
+        assert_eq!((report.restored, report.skipped_newer), (0, vec![newer.clone()]));
+        assert_eq!(std::fs::read_to_string(&newer).unwrap(), "changed since");
+        let report = backupper.restore(&options(None, true)).unwrap();
+        assert_eq!((report.restored, report.skipped_newer.len()), (3, 0));
+        assert_eq!(std::fs::read_to_string(&newer).unwrap(), "backed up a");
+    }
+
+    #[test]
+    fn paths_leaving_the_target_are_never_restored() {
+        let options = RestoreOptions {
+            backup: PathBuf::new(),
+            target: PathBuf::from("/restore"),
+            prefix: None,
+            force: false,
+            key_file: None,
+        };
+        assert!(Backupper::is_wanted(Path::new("docs/a.txt"), &options));
+        assert!(!Backupper::is_wanted(Path::new("../a.txt"), &options));
+        assert!(!Backupper::is_wanted(Path::new("/etc/passwd"), &options));
+        assert!(!Backupper::is_wanted(Path::new("docs/../../a.txt"), &options));
+    }
+}
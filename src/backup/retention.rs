@@ -0,0 +1,238 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use chrono::{Duration, NaiveDateTime};
+
+use super::journal::{journal_entries, JOURNAL_FILENAME};
+use super::manifest::{Manifest, MANIFEST_FILENAME};
+use super::mirror::QUARANTINE_DIR;
+use super::repository::{Repository, CHUNKS_DIR};
+use super::snapshot::Snapshot;
+use super::{Backupper, BackupperError};
+use crate::config::RetentionPolicy;
+
+// What the prune step removed from the destination
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    // Names of the removed snapshots, oldest first
+    pub removed: Vec<String>,
+    // Bytes actually released on the destination, hard links still used elsewhere excluded
+    pub freed: u64,
+}
+
+// Snapshots of the list (oldest first) that no rule of the policy keeps. Only complete snapshots count
+// for the rules: an incomplete one is kept until a complete snapshot is newer, then it expires
+pub fn expired_snapshots(snapshots: &[Snapshot], policy: &RetentionPolicy, now: NaiveDateTime) -> Vec<Snapshot> {
+    let complete: HashSet<&Path> = snapshots.iter().filter(|s| s.is_complete()).map(|s| s.path.as_path()).collect();
+    let newest_first: Vec<&Snapshot> = snapshots.iter().rev().filter(|s| complete.contains(s.path.as_path())).collect();
+    let newest_complete = newest_first.first().map(|s| s.time);
+    let mut kept: HashSet<&Path> = HashSet::new();
+
+    kept.extend(newest_first.iter().take(policy.keep_last).map(|s| s.path.as_path()));
+    if policy.keep_within_days > 0 {
+        let limit = now - Duration::days(policy.keep_within_days as i64);
+        kept.extend(newest_first.iter().filter(|s| s.time >= limit).map(|s| s.path.as_path()));
+    }
+    // The newest snapshot of each period counts, until enough periods are covered
+    for (count, period) in [
+        (policy.keep_daily, "%Y-%m-%d"),
+        (policy.keep_weekly, "%G-W%V"),
+        (policy.keep_monthly, "%Y-%m"),
+    ] {
+        let mut last_period = None;
+        let mut periods = 0;
+        for snapshot in &newest_first {
+            if periods == count {
+                break;
+            }
+            let current = snapshot.time.format(period).to_string();
+            if last_period.as_ref() != Some(&current) {
+                kept.insert(snapshot.path.as_path());
+                last_period = Some(current);
+                periods += 1;
+            }
+        }
+    }
+
+    snapshots
+        .iter()
+        .filter(|s| !kept.contains(s.path.as_path()))
+        .filter(|s| complete.contains(s.path.as_path()) || newest_complete.is_some_and(|time| s.time < time))
+        .cloned()
+        .collect()
+}
+
+// Size of the files under path that deleting it would release: a file still hard linked from
+// another snapshot only goes away with its last link
+fn freed_size(path: &Path) -> Result<u64, std::io::Error> {
+    let meta = std::fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        let mut size = 0;
+        for entry in std::fs::read_dir(path)? {
+            size += freed_size(&entry?.path())?;
+        }
+        return Ok(size);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if meta.nlink() > 1 {
+            return Ok(0);
+        }
+    }
+    Ok(meta.len())
+}
+
+impl Backupper {
+    // Delete the snapshots of the profile expired by the retention policy, never touching the one just created.
+    // The snapshots of other profiles backing up to the same destination follow their own policy
+    pub fn prune(&self, current: &Snapshot) -> Result<PruneReport, BackupperError> {
+        let mut report = PruneReport::default();
+        let policy = &self.backup_config.retention;
+        if !policy.is_enabled() {
+            return Ok(report);
+        }
+        let destination = Path::new(&self.backup_config.destination);
+        let snapshots = self.profile_snapshots(destination)?;
+
+        for snapshot in expired_snapshots(&snapshots, policy, current.time) {
+            if snapshot.path == current.path {
+                continue;
+            }
+            // Files deleted from the source during that run go with it
+            let quarantine = destination.join(QUARANTINE_DIR).join(snapshot.name());
+            for path in [&snapshot.path, &quarantine] {
+                if path.exists() {
+                    report.freed += freed_size(path)?;
+                    std::fs::remove_dir_all(path)?;
+                }
+            }
+            println!("Snapshot {} expired and removed", snapshot.name());
+            report.removed.push(snapshot.name());
+        }
+
+        if !report.removed.is_empty() && destination.join(CHUNKS_DIR).is_dir() {
+            report.freed += self.collect_chunks(destination)?;
+        }
+        Ok(report)
+    }
+
+    // Remove the repository chunks no remaining snapshot references, whatever profile wrote it.
+    // An interrupted run has no manifest yet: the chunks of the files it finished are listed in its journal
+    fn collect_chunks(&self, destination: &Path) -> Result<u64, BackupperError> {
+        let mut referenced = HashSet::new();
+        for snapshot in Snapshot::list(destination)? {
+            let readable = self.same_encryption(&snapshot);
+            let manifest = snapshot.path.join(MANIFEST_FILENAME).exists().then(|| {
+                readable.then(|| Manifest::load(&snapshot, self.cipher())).flatten().map(|m| m.files)
+            });
+            let journal = snapshot.path.join(JOURNAL_FILENAME).is_file().then(|| {
+                readable.then(|| journal_entries(&snapshot, self.cipher())).flatten()
+            });
+            for entries in [manifest, journal].into_iter().flatten() {
+                match entries {
+                    Some(entries) => referenced.extend(entries.into_iter().filter_map(|e| e.chunks).flatten()),
+                    // A chunk list that can't be read could reference any chunk: keep them all
+                    None => {
+                        println!("Chunks of snapshot {} can't be read, unused chunks are kept", snapshot.name());
+                        return Ok(0);
+                    }
+                }
+            }
+        }
+        Ok(Repository::open(destination, self.cipher()).remove_unreferenced(&referenced)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::snapshot::PARTIAL_FILENAME;
+    use crate::config::BackupConfig;
+
+    fn time(time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    // Complete snapshots at the given times in destination, oldest first
+    fn snapshots(destination: &Path, times: &[&str]) -> Vec<Snapshot> {
+        times
+            .iter()
+            .map(|t| {
+                let path = destination.join(time(t).format("%Y-%m-%d_%H-%M-%S").to_string());
+                std::fs::create_dir_all(&path).unwrap();
+                std::fs::write(path.join(MANIFEST_FILENAME), "{}").unwrap();
+                Snapshot::open(&path).unwrap()
+            })
+            .collect()
+    }
+
+    fn expired(snapshots: &[Snapshot], policy: &RetentionPolicy) -> Vec<String> {
+        let expired = expired_snapshots(snapshots, policy, time("2026-03-02 12:00"));
+        expired.iter().map(|s| s.time.format("%Y-%m-%d %H:%M").to_string()).collect()
+    }
+
+    const TIMES: [&str; 7] = [
+        "2026-01-01 10:00",
+        "2026-01-15 10:00",
+        "2026-02-01 10:00",
+        "2026-02-01 12:00",
+        "2026-03-01 10:00",
+        "2026-03-02 10:00",
+        "2026-03-02 11:00",
+    ];
+
+    #[test]
+    fn a_snapshot_kept_by_any_rule_stays() {
+        let destination = tempfile::tempdir().unwrap();
+        let snapshots = snapshots(destination.path(), &TIMES);
+        let policy = RetentionPolicy { keep_last: 1, keep_daily: 2, keep_monthly: 3, ..Default::default() };
+        assert_eq!(expired(&snapshots, &policy), ["2026-01-01 10:00", "2026-02-01 10:00", "2026-03-02 10:00"]);
+        let policy = RetentionPolicy { keep_within_days: 1, ..Default::default() };
+        assert_eq!(expired(&snapshots, &policy).len(), 5);
+        let policy = RetentionPolicy { keep_weekly: 2, ..Default::default() };
+        assert_eq!(expired(&snapshots, &policy).len(), 5);
+    }
+
+    #[test]
+    fn incomplete_snapshots_never_displace_complete_ones() {
+        let destination = tempfile::tempdir().unwrap();
+        let snapshots = snapshots(destination.path(), &TIMES);
+        let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+        // The newest one was cancelled: the two complete ones before it are kept, and it stays too
+        std::fs::write(snapshots[6].path.join(PARTIAL_FILENAME), "").unwrap();
+        assert_eq!(expired(&snapshots, &policy).len(), 4);
+        assert!(!expired(&snapshots, &policy).contains(&"2026-03-01 10:00".to_string()));
+        // Interrupted or without manifest, an older snapshot expires once a complete one is newer
+        std::fs::remove_file(snapshots[6].path.join(PARTIAL_FILENAME)).unwrap();
+        std::fs::write(snapshots[5].path.join(crate::backup::journal::JOURNAL_FILENAME), "").unwrap();
+        std::fs::remove_file(snapshots[4].path.join(MANIFEST_FILENAME)).unwrap();
+        assert_eq!(expired(&snapshots, &policy).len(), 5);
+        assert!(!expired(&snapshots, &policy).contains(&"2026-02-01 12:00".to_string()));
+    }
+
+    #[test]
+    fn chunks_listed_in_the_journal_of_an_interrupted_run_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path();
+        let snapshots = snapshots(destination, &TIMES[..1]);
+        let (journaled, unused) = ("aa".repeat(32), "bb".repeat(32));
+        for id in [&journaled, &unused] {
+            let dir = destination.join(CHUNKS_DIR).join(&id[..2]);
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join(id), "chunk").unwrap();
+        }
+        let header = serde_json::json!({"profile": "other", "format": "Repository", "files": ["a.txt"]});
+        let entry = serde_json::json!({
+            "path": "a.txt", "size": 5, "modified_secs": 0, "modified_nanos": 0,
+            "readonly": false, "mode": null, "hash": "", "chunks": [journaled],
+        });
+        std::fs::remove_file(snapshots[0].path.join(MANIFEST_FILENAME)).unwrap();
+        std::fs::write(snapshots[0].path.join(JOURNAL_FILENAME), format!("{}\n{}\n", header, entry)).unwrap();
+
+        let backupper = Backupper::with_config(BackupConfig::with_defaults("test"));
+        assert_eq!(backupper.collect_chunks(destination).unwrap(), 5);
+        assert!(destination.join(CHUNKS_DIR).join("aa").join(&journaled).exists());
+        assert!(!destination.join(CHUNKS_DIR).join("bb").join(&unused).exists());
+    }
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{Duration, Local, NaiveDateTime};
+use rebackup::{walker, WalkerConfig};
+
+use super::crypto::EncryptionInfo;
+use super::journal::{journal_profile, JOURNAL_FILENAME};
+use super::manifest::{Manifest, MANIFEST_FILENAME};
+use super::{Backupper, BackupperError};
+use crate::config::OutputFormat;
+
+// Name format of the snapshot directories created under the destination
+pub const SNAPSHOT_NAME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+// Folder of a snapshot holding the backed up files (log and metadata stay next to it)
+pub const SNAPSHOT_DATA_DIR: &str = "data";
+// Written in a snapshot whose backup was cancelled, failed on some files or left some out
+pub const PARTIAL_FILENAME: &str = "partial";
+// Name of the profile that wrote the snapshot: several profiles can back up to the same destination
+pub const PROFILE_FILENAME: &str = "profile";
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub time: NaiveDateTime,
+}
+
+impl Snapshot {
+    // Create a new, empty snapshot directory named after the current time
+    pub fn create(destination: &Path) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(destination)?;
+        let mut time = Local::now().naive_local();
+        loop {
+            let path = destination.join(time.format(SNAPSHOT_NAME_FORMAT).to_string());
+            match std::fs::create_dir(&path) {
+                Ok(_) => return Ok(Self { path, time }),
+                // Two backups within the same second: move on to the next free name
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    time += Duration::seconds(1);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Open an existing snapshot directory, if its name matches the snapshot format
+    pub fn open(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let time = NaiveDateTime::parse_from_str(name, SNAPSHOT_NAME_FORMAT).ok()?;
+        if !path.is_dir() {
+            return None;
+        }
+        Some(Self {
+            path: path.to_path_buf(),
+            time,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.time.format(SNAPSHOT_NAME_FORMAT).to_string()
+    }
+
+    // Whether the backup writing the snapshot went through: its manifest was saved, and it was
+    // neither interrupted (its journal is still there) nor partial
+    pub fn is_complete(&self) -> bool {
+        self.path.join(MANIFEST_FILENAME).is_file()
+            && !self.path.join(JOURNAL_FILENAME).exists()
+            && !self.path.join(PARTIAL_FILENAME).exists()
+    }
+
+    pub fn data_dir(&self) -> PathBuf {
+        self.path.join(SNAPSHOT_DATA_DIR)
+    }
+
+    // Archive holding the files of the snapshot, for the archive output formats
+    pub fn archive_path(&self, format: OutputFormat) -> Option<PathBuf> {
+        let extension = format.extension()?;
+        Some(self.path.join(format!("{}.{}", SNAPSHOT_DATA_DIR, extension)))
+    }
+
+    // Archive of an existing snapshot, None if its files are stored as a plain directory
+    pub fn archive(&self) -> Option<PathBuf> {
+        [OutputFormat::TarZstd, OutputFormat::TarGzip, OutputFormat::Zip]
+            .into_iter()
+            .filter_map(|format| self.archive_path(format))
+            .find(|path| path.is_file())
+    }
+
+    // Files stored in the snapshot, as paths under its data folder
+    pub fn files(&self) -> Result<Vec<PathBuf>, BackupperError> {
+        let data_dir = self.data_dir();
+        if !data_dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let canonical = std::fs::canonicalize(&data_dir)?;
+        let mut walker_config = WalkerConfig::new(Vec::new());
+        walker_config.drop_empty_dirs = true;
+        let data = walker::walk(&data_dir, &walker_config)?
+            .into_iter()
+            .map(|x| match x.strip_prefix(&canonical) {
+                Ok(rel) => data_dir.join(rel),
+                Err(_) => x,
+            })
+            .collect();
+        Ok(data)
+    }
+
+    // List the snapshots stored under the destination, oldest first
+    pub fn list(destination: &Path) -> Result<Vec<Self>, std::io::Error> {
+        let mut snapshots = Vec::new();
+        if !destination.is_dir() {
+            return Ok(snapshots);
+        }
+        for entry in std::fs::read_dir(destination)? {
+            if let Some(snapshot) = Self::open(&entry?.path()) {
+                snapshots.push(snapshot);
+            }
+        }
+        snapshots.sort_by_key(|s| s.time);
+        Ok(snapshots)
+    }
+
+    pub fn latest(destination: &Path) -> Option<Self> {
+        Self::list(destination).ok()?.pop()
+    }
+}
+
+impl Backupper {
+    // Snapshots of the destination written by the current profile, oldest first
+    pub(super) fn profile_snapshots(&self, destination: &Path) -> Result<Vec<Snapshot>, std::io::Error> {
+        let name = &self.backup_config.name;
+        let snapshots = Snapshot::list(destination)?.into_iter();
+        Ok(snapshots.filter(|s| self.snapshot_profile(s).as_ref() == Some(name)).collect())
+    }
+
+    // Profile that wrote a snapshot. For snapshots written before it was recorded on its own, it is read
+    // from the manifest or the journal, if the current key can decrypt them
+    fn snapshot_profile(&self, snapshot: &Snapshot) -> Option<String> {
+        if let Ok(profile) = std::fs::read_to_string(snapshot.path.join(PROFILE_FILENAME)) {
+            return Some(profile);
+        }
+        let cipher = match EncryptionInfo::load(snapshot).ok()? {
+            Some(info) => Some(self.cipher().filter(|c| c.info().key_id == info.key_id)?),
+            None => None,
+        };
+        Manifest::load_profile(snapshot, cipher).or_else(|| journal_profile(snapshot, cipher))
+    }
+}
@@ -0,0 +1,382 @@
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use super::Backupper;
+use crate::config::BackupConfig;
+
+// Room given to a (non thin) LVM snapshot for the blocks of the origin changed during the backup
+#[cfg(target_os = "linux")]
+const LVM_SNAPSHOT_SIZE: &str = "20%ORIGIN";
+// Inode number of the root folder of every btrfs subvolume
+#[cfg(target_os = "linux")]
+const BTRFS_SUBVOLUME_INODE: u64 = 256;
+// LVM snapshots are mounted in a private folder created here, where only root can write
+#[cfg(target_os = "linux")]
+const MOUNT_PARENT: &str = "/run";
+
+enum SnapshotKind {
+    Btrfs,
+    // Snapshot volume (vg/lv) of an LVM logical volume, mounted read-only on the snapshot path
+    Lvm { volume: String },
+}
+
+// Read-only snapshot of the filesystem holding some of the sources, which are backed up from it
+pub struct SourceSnapshot {
+    kind: SnapshotKind,
+    // Folder that was snapshotted (the btrfs subvolume or the mount point of the logical volume) and its device
+    origin: PathBuf,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    device: u64,
+    // The same folder in the snapshot
+    path: PathBuf,
+    // Index and configured path of the sources read from the snapshot
+    sources: Vec<(usize, String)>,
+}
+
+// Run an administration tool, its error output being the error
+fn run<S: AsRef<OsStr>>(program: &str, args: &[S]) -> Result<String, String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("{} could not be run: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(target_os = "linux")]
+struct Mount {
+    point: PathBuf,
+    fs_type: String,
+    device: String,
+}
+
+// Spaces, tabs, newlines and backslashes of /proc/self/mountinfo are written as octal escapes (\040)
+#[cfg(target_os = "linux")]
+fn unescape(field: &str) -> String {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < field.len() {
+        let code = field.get(i + 1..i + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match code {
+            Some(code) if field.as_bytes()[i] == b'\\' => {
+                out.push(code);
+                i += 4;
+            }
+            _ => {
+                out.push(field.as_bytes()[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Mount holding path: the deepest mount point above it, the last mounted one hiding the others
+#[cfg(target_os = "linux")]
+fn mount_of(path: &Path) -> Option<Mount> {
+    mount_in(&std::fs::read_to_string("/proc/self/mountinfo").ok()?, path)
+}
+
+// Same, among the lines of a mountinfo file
+#[cfg(target_os = "linux")]
+fn mount_in(mountinfo: &str, path: &Path) -> Option<Mount> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            // The optional fields before the "-" separator vary in number
+            let fields: Vec<&str> = line.split(' ').collect();
+            let separator = fields.iter().position(|f| *f == "-")?;
+            Some(Mount {
+                point: PathBuf::from(unescape(fields.get(4)?)),
+                fs_type: fields.get(separator + 1)?.to_string(),
+                device: unescape(fields.get(separator + 2)?),
+            })
+        })
+        .filter(|mount| path.starts_with(&mount.point))
+        .max_by_key(|mount| mount.point.components().count())
+}
+
+#[cfg(target_os = "linux")]
+fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|meta| meta.dev())
+}
+
+// Snapshot the subvolume holding source next to its other files, hidden under a dotted name
+#[cfg(target_os = "linux")]
+fn btrfs_snapshot(source: &Path, mount: &Mount, name: &str) -> Result<(PathBuf, PathBuf), String> {
+    use std::os::unix::fs::MetadataExt;
+    let subvolume = source
+        .ancestors()
+        .take_while(|dir| dir.starts_with(&mount.point))
+        .find(|dir| std::fs::metadata(dir).is_ok_and(|meta| meta.ino() == BTRFS_SUBVOLUME_INODE))
+        .ok_or_else(|| format!("no btrfs subvolume holds {}", source.display()))?;
+    let path = subvolume.join(format!(".{}", name));
+    // Left by a backup that was interrupted
+    if path.exists() {
+        let _ = run("btrfs", &[OsStr::new("subvolume"), OsStr::new("delete"), path.as_os_str()]);
+    }
+    let args = [OsStr::new("subvolume"), OsStr::new("snapshot"), OsStr::new("-r"), subvolume.as_os_str(), path.as_os_str()];
+    run("btrfs", &args)?;
+    Ok((subvolume.to_path_buf(), path))
+}
+
+#[cfg(target_os = "linux")]
+fn is_mount_point(dir: &Path) -> bool {
+    mount_of(dir).is_some_and(|mount| mount.point == dir)
+}
+
+// New folder to mount a snapshot on, as mkdtemp makes them: a random name that is never reused,
+// only accessible to the user running the backup
+#[cfg(target_os = "linux")]
+fn create_mount_dir(name: &str) -> Result<PathBuf, String> {
+    use chacha20poly1305::aead::rand_core::RngCore;
+    use chacha20poly1305::aead::OsRng;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    for _ in 0..16 {
+        let dir = Path::new(MOUNT_PARENT).join(format!("{}.{:016x}", name, OsRng.next_u64()));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {
+                let meta = dir.symlink_metadata().map_err(|e| format!("{} could not be read: {}", dir.display(), e))?;
+                // SAFETY: geteuid has no preconditions and can't fail
+                let owner = unsafe { libc::geteuid() };
+                if !meta.file_type().is_dir() || meta.uid() != owner || meta.mode() & 0o077 != 0 {
+                    return Err(format!("{} is not a private folder", dir.display()));
+                }
+                return Ok(dir);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(format!("{} could not be created: {}", dir.display(), e)),
+        }
+    }
+    Err(format!("no free folder name for {} in {}", name, MOUNT_PARENT))
+}
+
+// Unmount and remove the mount folders of a snapshot, including those left by a backup that was interrupted
+#[cfg(target_os = "linux")]
+fn remove_mount_dirs(name: &str) -> Result<(), String> {
+    let prefix = format!("{}.", name);
+    let entries = std::fs::read_dir(MOUNT_PARENT).map_err(|e| format!("{} could not be read: {}", MOUNT_PARENT, e))?;
+    for entry in entries.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+            continue;
+        }
+        let dir = entry.path();
+        if is_mount_point(&dir) {
+            run("umount", &[&dir])?;
+        }
+        let _ = std::fs::remove_dir(&dir);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn remove_lvm_snapshot(volume: &str) -> Result<(), String> {
+    let name = volume.rsplit('/').next().unwrap_or(volume);
+    remove_mount_dirs(name)?;
+    run("lvremove", &["-f", volume])?;
+    Ok(())
+}
+
+// Snapshot the logical volume mounted on mount, and mount the snapshot read-only in a private folder
+#[cfg(target_os = "linux")]
+fn lvm_snapshot(mount: &Mount, name: &str) -> Result<(String, PathBuf), String> {
+    let info = run("lvs", &["--noheadings", "-o", "vg_name,lv_name,lv_attr", &mount.device])?;
+    let (group, origin, attributes) = match info.split_whitespace().collect::<Vec<_>>()[..] {
+        [group, origin, attributes] => (group.to_string(), origin.to_string(), attributes.to_string()),
+        _ => return Err(format!("{} is not a logical volume", mount.device)),
+    };
+    let volume = format!("{}/{}", group, name);
+    // Left by a backup that was interrupted
+    let _ = remove_lvm_snapshot(&volume);
+
+    let origin = format!("{}/{}", group, origin);
+    // Thin snapshots take their room in the pool, and are not activated unless told so
+    match attributes.starts_with('V') {
+        true => run("lvcreate", &["-s", "-kn", "-n", name, &origin])?,
+        false => run("lvcreate", &["-s", "-l", LVM_SNAPSHOT_SIZE, "-n", name, &origin])?,
+    };
+    // XFS refuses to mount a second filesystem with the same UUID
+    let options = if mount.fs_type == "xfs" { "ro,nouuid" } else { "ro" };
+    let device = format!("/dev/{}", volume);
+    let mounted = create_mount_dir(name).and_then(|mount_dir| {
+        run("mount", &[OsStr::new("-o"), OsStr::new(options), OsStr::new(&device), mount_dir.as_os_str()])?;
+        Ok(mount_dir)
+    });
+    match mounted {
+        Ok(mount_dir) => Ok((volume, mount_dir)),
+        Err(e) => {
+            let _ = remove_lvm_snapshot(&volume);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn take_snapshot(source: &Path, name: &str) -> Result<SourceSnapshot, String> {
+    let mount = mount_of(source).ok_or_else(|| format!("{} is not mounted", source.display()))?;
+    let (kind, origin, path) = match mount.fs_type.as_str() {
+        "btrfs" => {
+            let (subvolume, path) = btrfs_snapshot(source, &mount, name)?;
+            (SnapshotKind::Btrfs, subvolume, path)
+        }
+        _ if mount.device.starts_with("/dev/") => {
+            let (volume, path) = lvm_snapshot(&mount, name)?;
+            (SnapshotKind::Lvm { volume }, mount.point, path)
+        }
+        other => return Err(format!("{} filesystems can't be snapshotted", other)),
+    };
+    let device = device_of(&origin).unwrap_or_default();
+    Ok(SourceSnapshot { kind, origin, device, path, sources: Vec::new() })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn take_snapshot(_source: &Path, _name: &str) -> Result<SourceSnapshot, String> {
+    Err("filesystem snapshots are only taken on Linux".to_string())
+}
+
+impl SourceSnapshot {
+    // Whether a source is on the snapshotted filesystem: btrfs gives each subvolume its own device number
+    fn holds(&self, source: &Path) -> bool {
+        #[cfg(target_os = "linux")]
+        return source.starts_with(&self.origin) && device_of(source) == Some(self.device);
+        #[cfg(not(target_os = "linux"))]
+        return false;
+    }
+
+    fn remove(&self) -> Result<(), String> {
+        match &self.kind {
+            SnapshotKind::Btrfs => {
+                run("btrfs", &[OsStr::new("subvolume"), OsStr::new("delete"), self.path.as_os_str()]).map(|_| ())
+            }
+            #[cfg(target_os = "linux")]
+            SnapshotKind::Lvm { volume } => remove_lvm_snapshot(volume),
+            #[cfg(not(target_os = "linux"))]
+            SnapshotKind::Lvm { .. } => Ok(()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.kind {
+            SnapshotKind::Btrfs => format!("btrfs snapshot of {}", self.origin.display()),
+            SnapshotKind::Lvm { volume } => format!("LVM snapshot {} of {}", volume, self.origin.display()),
+        }
+    }
+}
+
+impl Backupper {
+    // Snapshots are named after the profile, so that those left by an interrupted backup are found and removed
+    fn snapshot_name(&self, index: usize) -> String {
+        let profile: String = self
+            .backup_config
+            .name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        format!("emergency_backup-{}-{}", profile, index)
+    }
+
+    // Read the sources from read-only snapshots of their filesystem, so that files written during the backup are
+    // copied as they were when it started. A source whose filesystem can't be snapshotted is read directly
+    pub(super) fn snapshot_sources(&mut self) {
+        if !self.backup_config.snapshot_sources {
+            return;
+        }
+        let mut snapshots: Vec<SourceSnapshot> = Vec::new();
+        for i in 0..self.backup_config.sources.len() {
+            let configured = self.backup_config.sources[i].path.clone();
+            let source = match std::fs::canonicalize(&configured) {
+                Ok(source) => source,
+                // Reported when the backup checks its sources
+                Err(_) => continue,
+            };
+            // Sources on the same filesystem share its snapshot
+            let index = match snapshots.iter().position(|s| s.holds(&source)) {
+                Some(index) => index,
+                None => match take_snapshot(&source, &self.snapshot_name(snapshots.len())) {
+                    Ok(snapshot) => {
+                        println!("Took a {}", snapshot.describe());
+                        snapshots.push(snapshot);
+                        snapshots.len() - 1
+                    }
+                    Err(e) => {
+                        println!("Warning: {} is backed up directly, its filesystem could not be snapshotted: {}", configured, e);
+                        continue;
+                    }
+                },
+            };
+            let snapshot = &mut snapshots[index];
+            let path = snapshot.path.join(source.strip_prefix(&snapshot.origin).unwrap());
+            snapshot.sources.push((i, configured));
+            self.backup_config.sources[i].path = path.to_string_lossy().into_owned();
+        }
+        self.source_snapshots = snapshots;
+    }
+
+    // Read the sources from their configured path again, and remove their snapshots
+    pub(super) fn release_sources(&mut self) {
+        for snapshot in std::mem::take(&mut self.source_snapshots) {
+            for (i, path) in &snapshot.sources {
+                self.backup_config.sources[*i].path = path.clone();
+            }
+            match snapshot.remove() {
+                Ok(()) => println!("Removed the {}", snapshot.describe()),
+                Err(e) => eprintln!("Error removing the {} ({}): {}", snapshot.describe(), snapshot.path.display(), e),
+            }
+        }
+    }
+
+    // Configuration recorded in the manifest: the sources at their configured path, even while read from snapshots
+    pub(super) fn recorded_config(&self) -> BackupConfig {
+        let mut config = self.backup_config.clone();
+        for (i, path) in self.source_snapshots.iter().flat_map(|s| &s.sources) {
+            config.sources[*i].path = path.clone();
+        }
+        config
+    }
+
+    // Filesystem snapshots the sources are read from, for the log
+    pub(super) fn source_snapshot_names(&self) -> Vec<String> {
+        self.source_snapshots.iter().map(|s| s.describe()).collect()
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw,errors=remount-ro
+35 22 0:30 /home /home rw,relatime shared:2 - btrfs /dev/sdb1 rw,subvol=/home
+36 35 253:1 / /home/me/My\\040Files rw,relatime shared:3 master:1 - ext4 /dev/mapper/vg-my\\134data rw
+37 22 0:32 / /home2 rw - tmpfs tmpfs rw
+";
+
+    #[test]
+    fn octal_escapes_of_mountinfo_are_decoded() {
+        assert_eq!(unescape("My\\040Files"), "My Files");
+        assert_eq!(unescape("a\\134b\\011c\\012"), "a\\b\tc\n");
+        // Anything else is kept as it is
+        assert_eq!(unescape("\\x41\\09\\04"), "\\x41\\09\\04");
+        assert_eq!(unescape("café\\040été"), "café été");
+    }
+
+    #[test]
+    fn the_deepest_mount_point_holds_a_path() {
+        let mount = |path: &str| {
+            let mount = mount_in(MOUNTINFO, Path::new(path)).unwrap();
+            (mount.point.to_string_lossy().into_owned(), mount.fs_type, mount.device)
+        };
+        let data = ("/home/me/My Files".to_string(), "ext4".to_string(), "/dev/mapper/vg-my\\data".to_string());
+        assert_eq!(mount("/home/me/My Files/notes.txt"), data);
+        assert_eq!(mount("/home/me/docs"), ("/home".to_string(), "btrfs".to_string(), "/dev/sdb1".to_string()));
+        assert_eq!(mount("/home2/a"), ("/home2".to_string(), "tmpfs".to_string(), "tmpfs".to_string()));
+        // Mount points are whole path components
+        assert_eq!(mount("/home2x").0, "/");
+        assert!(mount_in("", Path::new("/home")).is_none());
+    }
+}
@@ -0,0 +1,89 @@
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use super::{Backupper, BackupperError};
+use crate::config::SourceConfig;
+
+impl Backupper {
+    // Reject the sources whose files couldn't be told apart in the snapshot
+    pub(super) fn check_sources(&self) -> Result<(), BackupperError> {
+        let sources = &self.backup_config.sources;
+        if sources.is_empty() {
+            return Err(BackupperError::BkpError("No source folder is set".to_string()));
+        }
+        let mut canonical: Vec<PathBuf> = Vec::new();
+        for source in sources {
+            if source.path.is_empty() || !Path::new(&source.path).is_dir() {
+                return Err(BackupperError::BkpError(format!(
+                    "Source {} is not a valid directory",
+                    source.path
+                )));
+            }
+            if source.name.is_empty() {
+                if sources.len() > 1 {
+                    return Err(BackupperError::BkpError(format!(
+                        "Source {} needs a name when several sources are backed up",
+                        source.path
+                    )));
+                }
+            } else if !matches!(Path::new(&source.name).components().collect::<Vec<_>>()[..], [Component::Normal(_)]) {
+                return Err(BackupperError::BkpError(format!("Invalid source name: {}", source.name)));
+            }
+            if sources.iter().filter(|s| s.name == source.name).count() > 1 {
+                return Err(BackupperError::BkpError(format!("Source name {} is used twice", source.name)));
+            }
+            let path = std::fs::canonicalize(&source.path)?;
+            if let Some(other) = canonical.iter().find(|c| path.starts_with(c) || c.starts_with(&path)) {
+                return Err(BackupperError::BkpError(format!(
+                    "Sources {} and {} overlap",
+                    other.display(),
+                    path.display()
+                )));
+            }
+            canonical.push(path);
+        }
+        Ok(())
+    }
+
+    // Source a file walked from the sources belongs to
+    pub(super) fn source_of(&self, file: &Path) -> Option<&SourceConfig> {
+        self.backup_config.sources.iter().find(|s| file.starts_with(&s.path))
+    }
+
+    // Path of a file of the sources inside the snapshot: the name of its source followed by its path in the source.
+    // A file found outside of every source can't be placed in the snapshot, it fails like an unreadable one
+    pub(super) fn rel_path(&self, file: &Path) -> Result<PathBuf, std::io::Error> {
+        match self.source_of(file) {
+            Some(source) => Ok(Path::new(&source.name).join(file.strip_prefix(&source.path).unwrap())),
+            None => Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("File outside of the sources: {}", file.display()),
+            )),
+        }
+    }
+
+    // Inverse of rel_path, None when the source of the file has been removed from the configuration
+    pub(super) fn source_path(&self, rel: &Path) -> Option<(&SourceConfig, PathBuf)> {
+        self.backup_config.sources.iter().find_map(|source| {
+            let inner = if source.name.is_empty() { Some(rel) } else { rel.strip_prefix(&source.name).ok() };
+            inner.map(|inner| (source, Path::new(&source.path).join(inner)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BackupConfig;
+
+    #[test]
+    fn files_outside_of_the_sources_are_an_error() {
+        let mut config = BackupConfig::with_defaults("test");
+        config.sources = vec![SourceConfig::new("/home/user/docs".to_string())];
+        let backupper = Backupper::with_config(config);
+        let file = Path::new("/home/user/docs/notes/todo.txt");
+        assert_eq!(backupper.rel_path(file).unwrap(), Path::new("docs/notes/todo.txt"));
+        assert_eq!(backupper.source_path(Path::new("docs/notes/todo.txt")).unwrap().1, file);
+        assert!(backupper.rel_path(Path::new("/home/user/documents/todo.txt")).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sysinfo::Disks;
+
+use super::crypto::Cipher;
+use super::manifest::{manifest_path, ManifestEntry};
+use super::plan::PlanAction;
+use super::snapshot::Snapshot;
+use super::{Backupper, BackupperError};
+use crate::config::{OutputFormat, SpaceShortage};
+
+// Allocation unit assumed for every new file, as even a tiny file takes a whole block
+const BLOCK_SIZE: u64 = 4096;
+// Kept free for the log, the folders and the other files written next to the data
+const METADATA_RESERVE: u64 = 64 * 1024;
+// Room taken by each file in the manifest, whether it is copied or not
+const MANIFEST_ENTRY_SIZE: u64 = 512;
+
+// Free space of the filesystem holding path, None if it can't be found
+pub fn available_space(path: &Path) -> Option<u64> {
+    // The destination may not exist yet: look at the closest existing folder
+    let existing = path.ancestors().find(|p| p.exists())?;
+    let canonical = std::fs::canonicalize(existing).ok()?;
+    // Mount points have no "\\?\" prefix on Windows
+    #[cfg(windows)]
+    let canonical = PathBuf::from(canonical.to_string_lossy().trim_start_matches(r"\\?\"));
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|d| canonical.starts_with(d.mount_point()))
+        .max_by_key(|d| d.mount_point().as_os_str().len())
+        .map(|d| d.available_space())
+}
+
+impl Backupper {
+    // Bytes a file takes on the destination once backed up, 0 when the previous copy is reused
+    fn required_space(
+        &self,
+        file: &Path,
+        previous: Option<&Snapshot>,
+        previous_entries: Option<&HashMap<String, ManifestEntry>>,
+    ) -> Result<u64, std::io::Error> {
+        // A link only takes an entry of the manifest, a file outside of the sources fails to copy
+        let rel = match self.rel_path(file) {
+            Ok(rel) if !self.is_link(file) => rel,
+            _ => return Ok(0),
+        };
+        let src_meta = std::fs::metadata(file)?;
+        let unchanged = match self.backup_config.output_format {
+            OutputFormat::Directory => match previous.map(|p| std::fs::metadata(self.stored_path(&p.data_dir(), &rel))) {
+                Some(Ok(prev_meta)) => {
+                    let prev_entry = previous_entries.and_then(|e| e.get(&manifest_path(&rel)));
+                    !self.file_changed(&src_meta, &prev_meta, prev_entry)?
+                }
+                _ => false,
+            },
+            OutputFormat::Repository => {
+                Self::repository_action(previous_entries, &rel, &src_meta)? == PlanAction::Unchanged
+            }
+            // Archives hold a full copy; compression only makes them smaller
+            _ => false,
+        };
+        if unchanged {
+            return Ok(0);
+        }
+        let stored = match self.cipher() {
+            Some(_) => Cipher::encrypted_len(src_meta.len()),
+            None => src_meta.len(),
+        };
+        Ok(stored.div_ceil(BLOCK_SIZE) * BLOCK_SIZE)
+    }
+
+    // Compare the space the backup needs with the free space of the destination and return the files
+    // to back up: all of them, or with CopyWhatFits the ones that fit, taken in the order of the list
+    pub(super) fn check_free_space(
+        &self,
+        files: &[PathBuf],
+        previous: Option<&Snapshot>,
+        previous_entries: Option<&HashMap<String, ManifestEntry>>,
+    ) -> Result<Vec<PathBuf>, BackupperError> {
+        let available = match available_space(Path::new(&self.backup_config.destination)) {
+            Some(available) => available,
+            None => {
+                println!("Free space of the destination unknown, skipping the space check");
+                return Ok(files.to_vec());
+            }
+        };
+        let mut required = Vec::with_capacity(files.len());
+        for file in files {
+            required.push(self.required_space(file, previous, previous_entries)?);
+        }
+        let metadata = METADATA_RESERVE + MANIFEST_ENTRY_SIZE * files.len() as u64;
+        let needed = metadata + required.iter().sum::<u64>();
+        println!("Space needed on the destination: {} bytes, {} bytes available", needed, available);
+        if needed <= available {
+            return Ok(files.to_vec());
+        }
+        if self.backup_config.space_shortage == SpaceShortage::Abort {
+            return Err(BackupperError::BkpError(format!(
+                "Not enough free space on the destination: {} bytes needed, {} bytes available",
+                needed, available
+            )));
+        }
+
+        let mut selected = Vec::new();
+        let mut skipped = self.skipped_files.lock().unwrap();
+        let mut left = available.saturating_sub(metadata);
+        for (file, size) in files.iter().zip(required) {
+            if size <= left {
+                left -= size;
+                selected.push(file.clone());
+            } else {
+                println!("Not enough free space left for {}, skipping it", file.display());
+                skipped.push(file.clone());
+            }
+        }
+        Ok(selected)
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use super::archive;
+use super::manifest::{manifest_path, Manifest};
+use super::repository::Repository;
+use super::snapshot::Snapshot;
+use super::{Backupper, BackupperError};
+use crate::config::OutputFormat;
+
+// Reader computing the SHA-256 of everything read through it
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    read: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            read: 0,
+        }
+    }
+
+    // Number of bytes read and their hash, as a lowercase hex string
+    pub fn finish(self) -> (u64, String) {
+        (self.read, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+// SHA-256 of the file content, as a lowercase hex string
+pub fn hash_file(path: &Path) -> Result<String, std::io::Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+impl Backupper {
+    // SHA-256 of the original content of a file stored in a snapshot, decrypting it if needed
+    pub(super) fn hash_stored_file(&self, dst: &Path) -> Result<String, std::io::Error> {
+        match self.cipher() {
+            Some(cipher) => {
+                let mut reader = HashingReader::new(cipher.decrypt(BufReader::new(File::open(dst)?)));
+                std::io::copy(&mut reader, &mut std::io::sink())?;
+                Ok(reader.finish().1)
+            }
+            None => hash_file(dst),
+        }
+    }
+
+    // Re-read every file copied by the last backup from the destination and compare it with the source
+    pub fn verify_backup(&self, snapshot: &Snapshot) -> Result<usize, BackupperError> {
+        let copied = self.copied_files.lock().unwrap().clone();
+        let mut mismatches = Vec::new();
+        let mut errors = Vec::new();
+
+        // Archived files are read back once, in the order they are stored
+        let archive = snapshot.archive();
+        let archived = match &archive {
+            Some(archive) => Some(archive::hash_entries(archive, self.cipher())?),
+            None => None,
+        };
+        // Files of a repository snapshot are rebuilt from the chunks listed in the manifest just written
+        let repository = Repository::open(Path::new(&self.backup_config.destination), self.cipher());
+        let chunks: Option<HashMap<String, Vec<String>>> = match self.backup_config.output_format {
+            OutputFormat::Repository => Manifest::load(snapshot, self.cipher())
+                .map(|m| m.files.into_iter().filter_map(|e| Some((e.path, e.chunks?))).collect()),
+            _ => None,
+        };
+        let dst_hash = |dst: &Path| match (&archive, &archived, &chunks) {
+            (Some(archive), Some(hashes), _) => {
+                let rel = manifest_path(dst.strip_prefix(archive).unwrap());
+                Ok(hashes.get(&rel).cloned().unwrap_or_default())
+            }
+            (_, _, Some(chunks)) => match chunks.get(&manifest_path(dst.strip_prefix(&snapshot.path).unwrap())) {
+                Some(chunks) => repository.read_file(chunks, &mut std::io::sink()),
+                None => Ok(String::new()),
+            },
+            _ => self.hash_stored_file(dst),
+        };
+
+        for (src, dst) in &copied {
+            match (hash_file(src), dst_hash(dst)) {
+                (Ok(src_hash), Ok(dst_hash)) => {
+                    if src_hash != dst_hash {
+                        eprintln!("Verification failed for {}", dst.display());
+                        mismatches.push(dst.clone());
+                    }
+                }
+                (Err(e), _) | (_, Err(e)) => {
+                    eprintln!("Error verifying file {:?}: {}", dst, e);
+                    errors.push(e);
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(BackupperError::IoError(errors));
+        }
+        if !mismatches.is_empty() {
+            return Err(BackupperError::VerifyError(mismatches));
+        }
+
+        println!("Verification completed: {} files match the source", copied.len());
+        Ok(copied.len())
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashSet;
+use std::fs::FileType;
+use std::path::{Path, PathBuf};
+
+use rebackup::{WalkerConfig, WalkerErr, WalkerItemType, WalkerRule, WalkerRuleErr, WalkerRuleResult};
+
+use crate::config::SymlinkPolicy;
+
+// Items of a source found by walk: the files to back up, and what was left out for a reason worth logging
+#[derive(Default)]
+pub struct Walk {
+    pub files: Vec<PathBuf>,
+    pub skipped: Vec<(PathBuf, &'static str)>,
+}
+
+// Same traversal and rules as rebackup's walker, which panics on sockets, FIFOs and devices and can only drop
+// symbolic links or replace them with their canonical target. Here every item keeps its path under the source,
+// so that a followed link is backed up where it is, and special files are reported instead of walked.
+// scanning is called with every folder about to be listed and the number of files found so far
+pub fn walk(
+    dir: &Path,
+    config: &WalkerConfig,
+    symlinks: SymlinkPolicy,
+    scanning: &mut dyn FnMut(&Path, usize),
+) -> Result<Walk, WalkerErr> {
+    let source = std::fs::canonicalize(dir).map_err(|e| WalkerErr::FailedToCanonicalize(dir.to_path_buf(), e))?;
+    if !source.is_dir() {
+        return Err(WalkerErr::DirNotFound);
+    }
+    let mut walker = Walker {
+        config,
+        symlinks,
+        source: &source,
+        scanning,
+        visited: HashSet::from([source.clone()]),
+        walk: Walk::default(),
+    };
+    walker.walk_dir(&source, &source)?;
+    Ok(walker.walk)
+}
+
+// Kind of a file that is neither a regular file, a directory nor a symbolic link
+fn special_kind(file_type: &FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_socket() {
+            return "socket";
+        } else if file_type.is_fifo() {
+            return "FIFO";
+        } else if file_type.is_block_device() {
+            return "block device";
+        } else if file_type.is_char_device() {
+            return "character device";
+        }
+    }
+    let _ = file_type;
+    "special file"
+}
+
+fn rule_failed(rule: &WalkerRule, item: &Path, err: WalkerRuleErr) -> WalkerErr {
+    WalkerErr::RuleFailedToRun {
+        rule_name: rule.name,
+        rule_description: rule.description.clone().unwrap_or_default(),
+        item_path: item.to_path_buf(),
+        err,
+    }
+}
+
+struct Walker<'a> {
+    config: &'a WalkerConfig,
+    symlinks: SymlinkPolicy,
+    source: &'a Path,
+    scanning: &'a mut dyn FnMut(&Path, usize),
+    // Canonical path of the directories walked so far, so that a followed link never loops
+    visited: HashSet<PathBuf>,
+    walk: Walk,
+}
+
+impl Walker<'_> {
+    fn skip(&mut self, item: PathBuf, reason: &'static str) {
+        println!("Skipping {} ({})", item.display(), reason);
+        self.walk.skipped.push((item, reason));
+    }
+
+    // canonical is where dir really is, it differs from dir below a followed link
+    fn walk_dir(&mut self, dir: &Path, canonical: &Path) -> Result<(), WalkerErr> {
+        (self.scanning)(dir, self.walk.files.len());
+        for entry in std::fs::read_dir(dir).map_err(WalkerErr::FailedToWalkDir)? {
+            let name = entry.map_err(WalkerErr::FailedToReadDirEntry)?.file_name();
+            self.walk_item(dir.join(&name), canonical.join(&name))?;
+        }
+        Ok(())
+    }
+
+    fn walk_item(&mut self, item: PathBuf, canonical: PathBuf) -> Result<(), WalkerErr> {
+        let file_type = item
+            .symlink_metadata()
+            .map_err(|e| WalkerErr::FailedToGetItemMetadata(item.clone(), e))?
+            .file_type();
+        let (item_type, canonical) = if file_type.is_symlink() {
+            match self.symlinks {
+                SymlinkPolicy::Skip => return Ok(()),
+                SymlinkPolicy::CopyAsLink => (WalkerItemType::Symlink, canonical),
+                SymlinkPolicy::Follow => match (std::fs::metadata(&item), std::fs::canonicalize(&item)) {
+                    (Ok(meta), Ok(target)) if meta.is_dir() => (WalkerItemType::Directory, target),
+                    (Ok(meta), Ok(target)) if meta.is_file() => (WalkerItemType::File, target),
+                    (Ok(meta), _) => {
+                        self.skip(item, special_kind(&meta.file_type()));
+                        return Ok(());
+                    }
+                    _ => {
+                        self.skip(item, "broken symbolic link");
+                        return Ok(());
+                    }
+                },
+            }
+        } else if file_type.is_dir() {
+            (WalkerItemType::Directory, canonical)
+        } else if file_type.is_file() {
+            (WalkerItemType::File, canonical)
+        } else {
+            self.skip(item, special_kind(&file_type));
+            return Ok(());
+        };
+
+        for rule in &self.config.rules {
+            if rule.only_for.is_none_or(|t| t == item_type) && (rule.matches)(&item, self.config, self.source) {
+                let result = (rule.action)(&item, self.config, self.source)
+                    .map_err(|e| rule_failed(rule, &item, WalkerRuleErr::Io(e)))?;
+                match result {
+                    WalkerRuleResult::StrError(e) => return Err(rule_failed(rule, &item, WalkerRuleErr::Str(e))),
+                    WalkerRuleResult::SkipRule | WalkerRuleResult::IncludeItem => {}
+                    WalkerRuleResult::IncludeItemAbsolute => break,
+                    WalkerRuleResult::ExcludeItem => return Ok(()),
+                    WalkerRuleResult::MapAsList(items, absolute) => {
+                        if item_type != WalkerItemType::Directory {
+                            return Err(WalkerErr::RuleMappedFileAsDir {
+                                rule_name: rule.name,
+                                rule_description: rule.description.clone().unwrap_or_default(),
+                                item_path: item,
+                            });
+                        }
+                        for mapped in items {
+                            let mapped = item.join(mapped);
+                            if absolute {
+                                self.walk.files.push(mapped);
+                            } else {
+                                let mapped_canonical = canonical.join(mapped.strip_prefix(&item).unwrap_or(&mapped));
+                                self.walk_item(mapped, mapped_canonical)?;
+                            }
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if item_type == WalkerItemType::Directory {
+            if !self.visited.insert(canonical.clone()) {
+                let reason = if file_type.is_symlink() {
+                    "symbolic link to a folder already backed up"
+                } else {
+                    "folder already backed up through a symbolic link"
+                };
+                self.skip(item, reason);
+                return Ok(());
+            }
+            self.walk_dir(&item, &canonical)
+        } else {
+            self.walk.files.push(item);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn walked(source: &Path, symlinks: SymlinkPolicy) -> Walk {
+        walk(source, &WalkerConfig::new(Vec::new()), symlinks, &mut |_, _| {}).unwrap()
+    }
+
+    #[test]
+    fn followed_links_never_loop_and_keep_their_path() {
+        let root = tempfile::tempdir().unwrap();
+        let (source, outside) = (root.path().join("source"), root.path().join("outside"));
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(source.join("sub/file.txt"), "inside").unwrap();
+        std::fs::write(outside.join("other.txt"), "outside").unwrap();
+        symlink(&source, source.join("sub/loop")).unwrap();
+        symlink(&outside, source.join("linked")).unwrap();
+        symlink(outside.join("other.txt"), source.join("alias.txt")).unwrap();
+        symlink(root.path().join("missing"), source.join("broken")).unwrap();
+        let source = std::fs::canonicalize(&source).unwrap();
+
+        let found = walked(&source, SymlinkPolicy::Follow);
+        let mut files = found.files.clone();
+        files.sort();
+        assert_eq!(files, [source.join("alias.txt"), source.join("linked/other.txt"), source.join("sub/file.txt")]);
+        let mut skipped = found.skipped;
+        skipped.sort();
+        assert_eq!(
+            skipped,
+            [
+                (source.join("broken"), "broken symbolic link"),
+                (source.join("sub/loop"), "symbolic link to a folder already backed up"),
+            ]
+        );
+
+        // Stored as links, nothing is followed
+        let mut files = walked(&source, SymlinkPolicy::CopyAsLink).files;
+        files.sort();
+        let links = ["alias.txt", "broken", "linked", "sub/file.txt", "sub/loop"].map(|rel| source.join(rel));
+        assert_eq!(files, links);
+        assert_eq!(walked(&source, SymlinkPolicy::Skip).files, [source.join("sub/file.txt")]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn special_files_are_reported_instead_of_walked() {
+        let root = tempfile::tempdir().unwrap();
+        let fifo = std::ffi::CString::new(root.path().join("fifo").to_str().unwrap()).unwrap();
+        // SAFETY: the path is a valid NUL-terminated string for the whole call
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let found = walked(root.path(), SymlinkPolicy::Follow);
+        assert!(found.files.is_empty());
+        assert_eq!(found.skipped, [(root.path().join("fifo"), "FIFO")]);
+    }
+}
@@ -1,80 +1,474 @@
 use std::{error::Error, fs::File, io::BufReader};
 
+use crate::backup::filters::parse_date;
+use crate::backup::patterns::invalid_patterns;
 use crate::launcher::{disable, enable, is_enabled};
 use crate::utils::get_abs_path;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 pub const CONFIG_FILE_PATH: &str = "emergency_backup/backup_info.json";
 pub const CPU_USAGE_LOG_PATH: &str = "emergency_backup/cpu_usage.log";
+// Profile of configuration files written before profiles existed
+pub const DEFAULT_PROFILE_NAME: &str = "default";
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+// Corner of the screen where the rectangle gesture starting a backup begins
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScreenCorner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl ScreenCorner {
+    pub const ALL: [ScreenCorner; 4] = [
+        ScreenCorner::TopLeft,
+        ScreenCorner::TopRight,
+        ScreenCorner::BottomLeft,
+        ScreenCorner::BottomRight,
+    ];
+
+    // Position of the corner on a screen of the given size
+    pub fn position(&self, width: i32, height: i32) -> (i32, i32) {
+        match self {
+            ScreenCorner::TopLeft => (0, 0),
+            ScreenCorner::TopRight => (width - 1, 0),
+            ScreenCorner::BottomLeft => (0, height - 1),
+            ScreenCorner::BottomRight => (width - 1, height - 1),
+        }
+    }
+
+    // Horizontal and vertical direction of the first two sides of the rectangle, away from the corner
+    pub fn direction(&self) -> (i32, i32) {
+        match self {
+            ScreenCorner::TopLeft => (1, 1),
+            ScreenCorner::TopRight => (-1, 1),
+            ScreenCorner::BottomLeft => (1, -1),
+            ScreenCorner::BottomRight => (-1, -1),
+        }
+    }
+}
+
+// How files deleted from the source are handled in the next backup
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    // Keep them in the backup forever
+    #[default]
+    Additive,
+    // Drop them from the backup, so that it mirrors the source
+    Mirror,
+}
+
+// How the files of a snapshot are stored on the destination
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    // Plain directory tree, unchanged files hard linked to the previous snapshot
+    #[default]
+    Directory,
+    // Single tar archive compressed with zstd
+    TarZstd,
+    // Single tar archive compressed with gzip
+    TarGzip,
+    // Single zip archive, opened natively by every OS
+    Zip,
+    // Deduplicated repository of content-defined chunks, shared by all the snapshots
+    Repository,
+}
+
+impl OutputFormat {
+    // Extension of the archive file, None when the snapshot is not a single archive
+    pub fn extension(&self) -> Option<&'static str> {
+        match self {
+            OutputFormat::Directory | OutputFormat::Repository => None,
+            OutputFormat::TarZstd => Some("tar.zst"),
+            OutputFormat::TarGzip => Some("tar.gz"),
+            OutputFormat::Zip => Some("zip"),
+        }
+    }
+}
+
+// Where the encryption key of the backup comes from
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionMode {
+    // Files are copied in the clear
+    #[default]
+    None,
+    // Key read from the file at key_file, to be kept away from the backup drive
+    KeyFile,
+    // Key derived from the passphrase in the EMERGENCY_BACKUP_PASSPHRASE environment variable, or else
+    // in the file at passphrase_file
+    Passphrase,
+}
+
+// What a backup does when the destination can't hold every file
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceShortage {
+    // Don't copy anything
+    #[default]
+    Abort,
+    // Copy the files in priority order, skipping the ones that don't fit anymore
+    CopyWhatFits,
+}
+
+// How symbolic links found in the sources are backed up
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    // Leave them out of the backup
+    #[default]
+    Skip,
+    // Back up what they point to, as if it was inside the source (loops are detected)
+    Follow,
+    // Store the link itself, recreated as a link on restore
+    CopyAsLink,
+}
+
+// Order of the files inside a priority tier
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOrder {
+    // As found while walking the source
+    #[default]
+    Walk,
+    // Most recently modified first
+    NewestFirst,
+    SmallestFirst,
+}
+
+// Which snapshots are kept after a successful backup, 0 disables a rule and all zeros keep everything
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // Most recent snapshots
+    #[serde(default)]
+    pub keep_last: usize,
+    // Latest snapshot of each of the most recent days, weeks and months having one
+    #[serde(default)]
+    pub keep_daily: usize,
+    #[serde(default)]
+    pub keep_weekly: usize,
+    #[serde(default)]
+    pub keep_monthly: usize,
+    // Every snapshot younger than this number of days
+    #[serde(default)]
+    pub keep_within_days: u32,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+// Folder backed up into its own subfolder of the snapshot
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq)]
+pub struct SourceConfig {
+    pub path: String,
+    // Subfolder of the snapshot receiving the files; empty stores them at the root, as configurations
+    // with a single source did
+    #[serde(default)]
+    pub name: String,
+    // Exclusions applied to this source only, on top of the global ones
+    #[serde(default)]
+    pub excluded_extensions: Vec<String>,
+    #[serde(default)]
+    pub excluded_directories: Vec<String>,
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+}
+
+impl SourceConfig {
+    // New source stored in a subfolder named after its folder
+    pub fn new(path: String) -> Self {
+        let name = std::path::Path::new(&path)
+            .file_name()
+            .map_or_else(|| "source".to_string(), |n| n.to_string_lossy().into_owned());
+        Self {
+            path,
+            name,
+            excluded_extensions: Vec::new(),
+            excluded_directories: Vec::new(),
+            exclude_patterns: Vec::new(),
+        }
+    }
+}
+
+// Configurations with a single "source" string are loaded as one source stored at the root
+fn deserialize_sources<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<SourceConfig>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Sources {
+        Single(String),
+        List(Vec<SourceConfig>),
+    }
+    Ok(match Sources::deserialize(deserializer)? {
+        Sources::Single(path) if path.is_empty() => Vec::new(),
+        Sources::Single(path) => vec![SourceConfig {
+            path,
+            name: String::new(),
+            excluded_extensions: Vec::new(),
+            excluded_directories: Vec::new(),
+            exclude_patterns: Vec::new(),
+        }],
+        Sources::List(sources) => sources,
+    })
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
 pub struct BackupConfig {
-    pub source: String,
+    // Name of the profile, chosen in the confirmation window or on the command line
+    #[serde(default = "default_profile_name")]
+    pub name: String,
+    // Corner where the rectangle gesture backing up this profile begins
+    #[serde(default)]
+    pub gesture: Option<ScreenCorner>,
+    #[serde(default, alias = "source", deserialize_with = "deserialize_sources")]
+    pub sources: Vec<SourceConfig>,
     pub destination: String,
     pub excluded_extensions: Vec<String>,
     pub excluded_directories: Vec<String>,
+    // Gitignore-style globs ("**/node_modules", "*.tmp", "!keep.tmp"), applied after the excluded
+    // extensions and directories; the last matching line wins
+    #[serde(default)]
+    pub exclude_patterns: Vec<String>,
+    // When not empty, only the files matching these globs are backed up
+    #[serde(default)]
+    pub include_patterns: Vec<String>,
+    // Also skip what the .gitignore and .backupignore files of the walked folders ignore
+    #[serde(default)]
+    pub use_ignore_files: bool,
+    // Only files within these sizes (in bytes, 0 for no limit) are backed up
+    #[serde(default)]
+    pub min_file_size: u64,
+    #[serde(default)]
+    pub max_file_size: u64,
+    // Only files modified on or after modified_after and before modified_before (YYYY-MM-DD, empty for no limit) are backed up
+    #[serde(default)]
+    pub modified_after: String,
+    #[serde(default)]
+    pub modified_before: String,
     pub log_filename: String,
+    #[serde(default)]
+    pub verify_backup: bool,
+    #[serde(default)]
+    pub sync_mode: SyncMode,
+    // In mirror mode, keep deleted files in the quarantine folder of the destination
+    #[serde(default = "default_true")]
+    pub quarantine_deleted: bool,
+    // Number of files copied at the same time (0 uses one thread per CPU)
+    #[serde(default = "default_worker_threads")]
+    pub worker_threads: usize,
+    // Play a sound at every quarter of the backup
+    #[serde(default = "default_true")]
+    pub progress_sounds: bool,
+    #[serde(default)]
+    pub output_format: OutputFormat,
+    #[serde(default)]
+    pub encryption: EncryptionMode,
+    // Path of the key file, the key itself is never stored here
+    #[serde(default)]
+    pub key_file: String,
+    // Path of a file holding the passphrase, for backups started without the environment variable
+    // (e.g. by the gesture, from autostart); like the key file, it must be kept away from the drive
+    #[serde(default)]
+    pub passphrase_file: String,
+    // Salt of the passphrase key derivation (not secret)
+    #[serde(default)]
+    pub encryption_salt: String,
+    #[serde(default)]
+    pub encrypt_file_names: bool,
+    // Files under these paths of the source, then files with these extensions, are backed up first:
+    // each line is a priority tier, in the given order, followed by a tier with the other files
+    #[serde(default)]
+    pub priority_paths: Vec<String>,
+    #[serde(default)]
+    pub priority_extensions: Vec<String>,
+    #[serde(default)]
+    pub priority_order: FileOrder,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub space_shortage: SpaceShortage,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    // Give the copies the owner, timestamps, extended attributes and ACLs of the source, and restore them
+    #[serde(default = "default_true")]
+    pub preserve_metadata: bool,
+    // Changed files of at least this size (in bytes, 0 to turn it off) only get their changed blocks
+    // rewritten over a copy of their previous version
+    #[serde(default)]
+    pub delta_min_size: u64,
+    // On Linux, read the sources from a read-only btrfs or LVM snapshot taken when the backup starts
+    #[serde(default)]
+    pub snapshot_sources: bool,
     #[serde(skip)]
     pub autostart_enabled: bool,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_worker_threads() -> usize {
+    4
+}
+
+fn default_profile_name() -> String {
+    DEFAULT_PROFILE_NAME.to_string()
+}
+
 impl BackupConfig {
+    // Default profile saved in the configuration file
     pub fn new() -> Self {
-        let mut app = Self {
-            source: "".to_string(),
+        let profiles = BackupProfiles::load();
+        let mut app = profiles.default_config().clone();
+        app.autostart_enabled = is_enabled();
+        app
+    }
+
+    // Saved profile with the given name
+    pub fn profile(name: &str) -> Option<Self> {
+        BackupProfiles::load().get(name).cloned()
+    }
+
+    // Profile with every option at its default value
+    pub fn with_defaults(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            gesture: None,
+            sources: Vec::new(),
             destination: "".to_string(),
             excluded_extensions: Vec::new(),
             excluded_directories: Vec::new(),
+            exclude_patterns: Vec::new(),
+            include_patterns: Vec::new(),
+            use_ignore_files: false,
+            min_file_size: 0,
+            max_file_size: 0,
+            modified_after: String::new(),
+            modified_before: String::new(),
             log_filename: "backup_log.txt".to_string(),
+            verify_backup: false,
+            sync_mode: SyncMode::Additive,
+            quarantine_deleted: true,
+            worker_threads: default_worker_threads(),
+            progress_sounds: true,
+            output_format: OutputFormat::Directory,
+            encryption: EncryptionMode::None,
+            key_file: String::new(),
+            passphrase_file: String::new(),
+            encryption_salt: String::new(),
+            encrypt_file_names: false,
+            priority_paths: Vec::new(),
+            priority_extensions: Vec::new(),
+            priority_order: FileOrder::Walk,
+            retention: RetentionPolicy::default(),
+            space_shortage: SpaceShortage::Abort,
+            symlinks: SymlinkPolicy::Skip,
+            preserve_metadata: true,
+            delta_min_size: 0,
+            snapshot_sources: false,
             autostart_enabled: false,
+        }
+    }
+}
+
+// Content of the configuration file: named backup profiles and the one used when none is chosen
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupProfiles {
+    pub profiles: Vec<BackupConfig>,
+    pub default_profile: String,
+}
+
+impl BackupProfiles {
+    // Profiles of the configuration file; a file written before profiles existed holds a single
+    // configuration, loaded as the default profile
+    pub fn load() -> Self {
+        let mut profiles = Self {
+            profiles: Vec::new(),
+            default_profile: DEFAULT_PROFILE_NAME.to_string(),
         };
+        if get_abs_path(CONFIG_FILE_PATH).exists() {
+            let file = File::open(get_abs_path(CONFIG_FILE_PATH)).expect("Unable to open file");
+            let reader = BufReader::new(file);
+            let loaded = serde_json::from_reader(reader).and_then(|json: serde_json::Value| {
+                if json.get("profiles").is_some() {
+                    serde_json::from_value(json)
+                } else {
+                    serde_json::from_value(json).map(|config: BackupConfig| Self {
+                        default_profile: config.name.clone(),
+                        profiles: vec![config],
+                    })
+                }
+            });
+            match loaded {
+                Ok(loaded_info) => profiles = loaded_info,
+                // Handle JSON parsing errors by initializing with default values
+                Err(e) => eprintln!("Error loading JSON: {:?}", e),
+            }
+        }
+        if profiles.profiles.is_empty() {
+            profiles.profiles.push(BackupConfig::with_defaults(&profiles.default_profile));
+        }
+        profiles
+    }
 
-        // Load previously saved information
-        app.load_info();
-        app
+    pub fn get(&self, name: &str) -> Option<&BackupConfig> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    pub fn default_config(&self) -> &BackupConfig {
+        self.get(&self.default_profile).unwrap_or(&self.profiles[0])
+    }
+
+    // Profile started by the rectangle gesture beginning in a corner of the screen: the one bound
+    // to it, or the default profile for the top left corner as before profiles existed
+    pub fn for_gesture(&self, corner: ScreenCorner) -> Option<&BackupConfig> {
+        self.profiles
+            .iter()
+            .find(|p| p.gesture == Some(corner))
+            .or_else(|| (corner == ScreenCorner::TopLeft).then(|| self.default_config()))
     }
 
-    pub fn save_info(&self) -> Result<(), Box<dyn Error>> {
-        if self.source.is_empty() || self.destination.is_empty() || self.log_filename.is_empty() {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                "Source, Destination, and Log Filename must be set before saving.",
-            )));
+    pub fn save(&self, autostart_enabled: bool) -> Result<(), Box<dyn Error>> {
+        let invalid = |message: String| Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+        for (i, profile) in self.profiles.iter().enumerate() {
+            if profile.name.is_empty() {
+                return Err(invalid("Every profile must have a name.".to_string()));
+            }
+            if profile.sources.is_empty() || profile.destination.is_empty() || profile.log_filename.is_empty() {
+                return Err(invalid(format!(
+                    "Sources, Destination, and Log Filename of profile {} must be set before saving.",
+                    profile.name
+                )));
+            }
+            let sources = profile.sources.iter().flat_map(|s| &s.exclude_patterns);
+            let patterns: Vec<String> = sources.chain(&profile.exclude_patterns).chain(&profile.include_patterns).cloned().collect();
+            if let Some(error) = invalid_patterns(&patterns).first() {
+                return Err(invalid(format!("Invalid pattern in profile {}: {}", profile.name, error)));
+            }
+            for date in [&profile.modified_after, &profile.modified_before] {
+                parse_date(date).map_err(|e| invalid(format!("Invalid date in profile {}: {}", profile.name, e)))?;
+            }
+            for other in &self.profiles[..i] {
+                if other.name == profile.name {
+                    return Err(invalid(format!("Two profiles are named {}.", profile.name)));
+                }
+                if profile.gesture.is_some() && other.gesture == profile.gesture {
+                    return Err(invalid(format!(
+                        "Profiles {} and {} use the same gesture.",
+                        other.name, profile.name
+                    )));
+                }
+            }
+        }
+        if self.get(&self.default_profile).is_none() {
+            return Err(invalid("The default profile doesn't exist.".to_string()));
         }
         let file = File::create(get_abs_path(CONFIG_FILE_PATH))?;
         serde_json::to_writer(file, self)?;
-        if self.autostart_enabled == true && is_enabled() == false {
+        if autostart_enabled && !is_enabled() {
             enable();
-        } else if self.autostart_enabled == false && is_enabled() == true {
+        } else if !autostart_enabled && is_enabled() {
             disable();
         }
         Ok(())
     }
-
-    fn load_info(&mut self) {
-        if get_abs_path(CONFIG_FILE_PATH).exists() {
-            let file = File::open(get_abs_path(CONFIG_FILE_PATH)).expect("Unable to open file");
-            let reader = BufReader::new(file);
-            match serde_json::from_reader(reader) {
-                Ok(loaded_info) => {
-                    *self = {
-                        self.autostart_enabled = is_enabled();
-                        loaded_info
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Error loading JSON: {:?}", e);
-                    // Handle JSON parsing errors by initializing with default values
-                    self.excluded_extensions = Vec::new();
-                    self.excluded_directories = Vec::new();
-                    self.autostart_enabled = is_enabled();
-                }
-            }
-        } else {
-            // File does not exist; initialize with default values
-            self.excluded_extensions = Vec::new();
-            self.excluded_directories = Vec::new();
-            self.autostart_enabled = is_enabled();
-        }
-    }
 }
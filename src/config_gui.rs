@@ -1,4 +1,9 @@
-use crate::config::BackupConfig;
+use crate::backup::plan::{BackupPlan, PlanAction};
+use crate::backup::Backupper;
+use crate::backup::filters::parse_date;
+use crate::backup::patterns::invalid_patterns;
+use crate::backup::crypto::{generate_key_file, generate_salt, PASSPHRASE_ENV};
+use crate::config::{BackupConfig, BackupProfiles, EncryptionMode, ScreenCorner, FileOrder, OutputFormat, SourceConfig, SpaceShortage, SymlinkPolicy, SyncMode};
 use crate::launcher::is_enabled;
 use eframe::egui;
 use eframe::egui::ColorImage;
@@ -7,15 +12,23 @@ use rfd::FileDialog;
 use std::error::Error;
 use std::fs::File;
 use std::io::BufReader;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
 use std::time::{Duration, Instant};
 use image::codecs::gif::GifDecoder;
 use crate::utils::load_icon;
 use crate::utils::get_project_path;
 
 pub struct BackupConfigGUI {
+    profiles: BackupProfiles,
+    selected: usize, // Index of the profile being edited, its changes are kept in config until another one is selected
+    default: usize, // Index of the default profile
     config: BackupConfig,
+    autostart_enabled: bool,
     save_message: Option<(String, Instant)>,
     show_instructions: bool,
+    plan: Option<Result<BackupPlan, String>>, // Preview of the backup with the current (unsaved) options
+    pending_plan: Option<Receiver<Result<BackupPlan, String>>>, // Preview being computed on a worker thread
     gif_frames: Vec<(Vec<ColorImage>, String)>, // Stores GIF frames and associated text
     current_frame_indices: Vec<usize>, // Current frame indices for each GIF
     last_frame_times: Vec<Instant>, // Last frame times for each GIF
@@ -25,11 +38,12 @@ pub struct BackupConfigGUI {
 
 impl BackupConfigGUI {
     pub fn new() -> Self {
-        let mut config = BackupConfig::new();
-        config.autostart_enabled = is_enabled();
+        let profiles = BackupProfiles::load();
+        let default = profiles.profiles.iter().position(|p| p.name == profiles.default_profile).unwrap_or(0);
+        let config = profiles.profiles[default].clone();
 
         let gif_paths_and_texts = vec![
-            ("assets/rectangle-command.gif", "First, draw a rectangle starting from the top left and follow all your screen of PC. You will receive the confirmation/or error thanks to audio messages."),
+            ("assets/rectangle-command.gif", "First, draw a rectangle starting from the top left (or from the corner bound to a profile) and follow all your screen of PC. You will receive the confirmation/or error thanks to audio messages."),
             ("assets/confirm.gif", "To confirm the backup, you have to draw the gesture below or click on confirm in the GUI dialog."),
             ("assets/cancel.gif", "If you don't want to go down, you have to draw the gesture below or selecting cancel in the GUI."),
         ];
@@ -42,9 +56,15 @@ impl BackupConfigGUI {
             .collect();
 
         Self {
+            profiles,
+            selected: default,
+            default,
             config,
+            autostart_enabled: is_enabled(),
             save_message: None,
             show_instructions: false,
+            plan: None,
+            pending_plan: None,
             gif_frames,
             current_frame_indices: vec![0; 3],
             last_frame_times: vec![Instant::now(); 3],
@@ -53,6 +73,81 @@ impl BackupConfigGUI {
         }
     }
 
+    // Keep the changes of the edited profile and start editing another one
+    fn select_profile(&mut self, index: usize) {
+        self.profiles.profiles[self.selected] = self.config.clone();
+        self.selected = index;
+        self.config = self.profiles.profiles[index].clone();
+        self.plan = None;
+        self.pending_plan = None;
+    }
+
+    fn add_profile(&mut self) {
+        let mut n = self.profiles.profiles.len() + 1;
+        while self.profiles.get(&format!("profile {}", n)).is_some() || self.config.name == format!("profile {}", n) {
+            n += 1;
+        }
+        self.profiles.profiles.push(BackupConfig::with_defaults(&format!("profile {}", n)));
+        self.select_profile(self.profiles.profiles.len() - 1);
+    }
+
+    fn remove_profile(&mut self) {
+        if self.profiles.profiles.len() > 1 {
+            self.profiles.profiles.remove(self.selected);
+            if self.default >= self.selected && self.default > 0 {
+                self.default -= 1;
+            }
+            self.selected = 0;
+            self.config = self.profiles.profiles[0].clone();
+            self.plan = None;
+            self.pending_plan = None;
+        }
+    }
+
+    fn save_profiles(&mut self) -> Result<(), Box<dyn Error>> {
+        self.profiles.profiles[self.selected] = self.config.clone();
+        self.profiles.default_profile = self.profiles.profiles[self.default].name.clone();
+        self.profiles.save(self.autostart_enabled)
+    }
+
+    // Add a source stored in a subfolder named after it, made unique among the other sources
+    fn add_source(&mut self, path: String) {
+        // A source of an old configuration is stored at the root of the backup, which only works alone
+        for source in self.config.sources.iter_mut().filter(|s| s.name.is_empty()) {
+            source.name = SourceConfig::new(source.path.clone()).name;
+        }
+        let mut source = SourceConfig::new(path);
+        let base = source.name.clone();
+        let mut n = 1;
+        while self.config.sources.iter().any(|s| s.name == source.name) {
+            n += 1;
+            source.name = format!("{}_{}", base, n);
+        }
+        self.config.sources.push(source);
+    }
+
+    // Walking the sources can take a while: the preview is computed away from the UI thread
+    fn start_plan(&mut self) {
+        let (sender, receiver) = channel();
+        let config = self.config.clone();
+        thread::spawn(move || {
+            let _ = sender.send(Backupper::with_config(config).plan().map_err(|e| format!("{:?}", e)));
+        });
+        self.plan = None;
+        self.pending_plan = Some(receiver);
+    }
+
+    fn poll_plan(&mut self) {
+        if let Some(receiver) = &self.pending_plan {
+            match receiver.try_recv() {
+                Ok(plan) => self.plan = Some(plan),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => self.plan = Some(Err("The preview stopped unexpectedly".to_string())),
+            }
+            self.pending_plan = None;
+        }
+    }
+
     fn set_save_message(&mut self, message: String) {
         self.save_message = Some((message, Instant::now()));
     }
@@ -72,6 +167,7 @@ impl eframe::App for BackupConfigGUI {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Request repaint every frame to ensure regular updates
         ctx.request_repaint();
+        self.poll_plan();
 
         let now = Instant::now();
         if now.duration_since(self.last_repaint_time) >= self.frame_duration {
@@ -122,15 +218,91 @@ impl eframe::App for BackupConfigGUI {
                     }
 
                     ui.add_space(5.0);
-                    ui.checkbox(&mut self.config.autostart_enabled, "Enable Autostart");
+                    ui.checkbox(&mut self.autostart_enabled, "Enable Autostart");
 
                     ui.add_space(5.0);
+                    let mut selected = None;
+                    ui.horizontal(|ui| {
+                        ui.label("Profile:");
+                        egui::ComboBox::from_id_source("profile").selected_text(&self.config.name).show_ui(ui, |ui| {
+                            for (i, profile) in self.profiles.profiles.iter().enumerate() {
+                                let name = if i == self.selected { &self.config.name } else { &profile.name };
+                                if ui.selectable_label(i == self.selected, name).clicked() {
+                                    selected = Some(i);
+                                }
+                            }
+                        });
+                        if ui.button("New Profile").clicked() {
+                            self.add_profile();
+                        }
+                        if ui.add_enabled(self.profiles.profiles.len() > 1, egui::Button::new("Delete Profile")).clicked() {
+                            self.remove_profile();
+                        }
+                    });
+                    if let Some(i) = selected.filter(|i| *i != self.selected) {
+                        self.select_profile(i);
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Profile name:");
+                        ui.text_edit_singleline(&mut self.config.name);
+                    });
+                    let mut is_default = self.default == self.selected;
+                    if ui.checkbox(&mut is_default, "Default profile (confirmation window and command line)").changed() && is_default {
+                        self.default = self.selected;
+                    }
+                    ui.horizontal(|ui| {
+                        ui.label("Rectangle gesture starting from:");
+                        egui::ComboBox::from_id_source("gesture")
+                            .selected_text(gesture_label(self.config.gesture))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.config.gesture, None, gesture_label(None));
+                                for corner in ScreenCorner::ALL {
+                                    ui.selectable_value(&mut self.config.gesture, Some(corner), gesture_label(Some(corner)));
+                                }
+                            });
+                    });
 
-                    ui.label(format!("Selected Source folder: {}", self.config.source));
+                    ui.add_space(5.0);
+
+                    ui.label("Source folders, each one stored in its own subfolder of the backup:");
+                    let mut removed = None;
+                    for (i, source) in self.config.sources.iter_mut().enumerate() {
+                        egui::CollapsingHeader::new(&source.path).id_source(("source", i)).show(ui, |ui| {
+                            ui.horizontal(|ui| {
+                                ui.label("Subfolder:");
+                                ui.text_edit_singleline(&mut source.name);
+                            });
+
+                            let extensions_str = source.excluded_extensions.join("\n");
+                            ui.label("File extensions to exclude from this folder only (on different lines):");
+                            let mut input_extensions = extensions_str.clone();
+                            ui.add(egui::TextEdit::multiline(&mut input_extensions).hint_text("Enter extensions on different lines").desired_rows(2));
+                            if input_extensions != extensions_str {
+                                source.excluded_extensions = input_extensions.split('\n').map(|s| s.trim().to_string()).collect();
+                            }
+
+                            let directories_str = source.excluded_directories.join("\n");
+                            ui.label("Directories to exclude from this folder only (on different lines):");
+                            let mut input_directories = directories_str.clone();
+                            ui.add(egui::TextEdit::multiline(&mut input_directories).hint_text("Enter directories on different lines").desired_rows(2));
+                            if input_directories != directories_str {
+                                source.excluded_directories = input_directories.split('\n').map(|s| s.trim().to_string()).collect();
+                            }
+
+                            patterns_editor(ui, "Glob patterns to exclude from this folder only, \"!\" to re-include (on different lines):", &mut source.exclude_patterns, 2);
+
+                            if ui.button("Remove Source Folder").clicked() {
+                                removed = Some(i);
+                            }
+                        });
+                    }
+                    if let Some(i) = removed {
+                        self.config.sources.remove(i);
+                    }
                     ui.add_space(3.0);
-                    if ui.button("Source Folder").clicked() {
+                    if ui.button("Add Source Folder").clicked() {
                         if let Some(folder) = FileDialog::new().pick_folder() {
-                            self.config.source = folder.display().to_string();
+                            self.add_source(folder.display().to_string());
                         }
                     }
                     ui.add_space(3.0);
@@ -147,6 +319,132 @@ impl eframe::App for BackupConfigGUI {
                     ui.add_space(3.0);
                     ui.text_edit_singleline(&mut self.config.log_filename);
 
+                    ui.add_space(3.0);
+                    ui.checkbox(&mut self.config.verify_backup, "Verify the copied files after the backup (slower)");
+                    ui.checkbox(
+                        &mut self.config.preserve_metadata,
+                        "Keep the owner, timestamps, extended attributes and ACLs of the files and folders",
+                    );
+                    ui.checkbox(
+                        &mut self.config.snapshot_sources,
+                        "Back up from a snapshot of the sources taken at the start (btrfs or LVM, Linux only)",
+                    );
+                    ui.checkbox(&mut self.config.progress_sounds, "Play a sound at every quarter of the backup");
+
+                    ui.add_space(3.0);
+                    ui.label("Store the backup as:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.config.output_format, OutputFormat::Directory, "Folder");
+                        ui.radio_value(&mut self.config.output_format, OutputFormat::TarZstd, "tar.zst");
+                        ui.radio_value(&mut self.config.output_format, OutputFormat::TarGzip, "tar.gz");
+                        ui.radio_value(&mut self.config.output_format, OutputFormat::Zip, "zip");
+                        ui.radio_value(&mut self.config.output_format, OutputFormat::Repository, "Deduplicated repository");
+                    });
+
+                    ui.add_space(3.0);
+                    ui.label("Encryption of the backup:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.config.encryption, EncryptionMode::None, "Off");
+                        ui.radio_value(&mut self.config.encryption, EncryptionMode::KeyFile, "Key file");
+                        ui.radio_value(&mut self.config.encryption, EncryptionMode::Passphrase, "Passphrase");
+                    });
+                    match self.config.encryption {
+                        EncryptionMode::KeyFile => {
+                            ui.label(format!("Selected key file (keep it away from the backup drive): {}", self.config.key_file));
+                            ui.horizontal(|ui| {
+                                if ui.button("Key File").clicked() {
+                                    if let Some(file) = FileDialog::new().pick_file() {
+                                        self.config.key_file = file.display().to_string();
+                                    }
+                                }
+                                if ui.button("Generate Key File").clicked() {
+                                    if let Some(file) = FileDialog::new().save_file() {
+                                        match generate_key_file(&file) {
+                                            Ok(_) => self.config.key_file = file.display().to_string(),
+                                            Err(e) => eprintln!("Failed to generate key file: {}", e),
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                        EncryptionMode::Passphrase => {
+                            // The salt is not secret, only the passphrase is kept out of the configuration
+                            if self.config.encryption_salt.is_empty() {
+                                self.config.encryption_salt = generate_salt();
+                            }
+                            ui.label(format!("The passphrase is read from the {} environment variable, or else from:", PASSPHRASE_ENV));
+                            ui.label(format!("Selected passphrase file (keep it away from the backup drive): {}", self.config.passphrase_file));
+                            if ui.button("Passphrase File").clicked() {
+                                if let Some(file) = FileDialog::new().pick_file() {
+                                    self.config.passphrase_file = file.display().to_string();
+                                }
+                            }
+                        }
+                        EncryptionMode::None => {}
+                    }
+                    ui.add_enabled(
+                        self.config.encryption != EncryptionMode::None,
+                        egui::Checkbox::new(&mut self.config.encrypt_file_names, "Encrypt file names too"),
+                    );
+
+                    ui.add_space(3.0);
+                    ui.label("Files deleted from the source:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.config.sync_mode, SyncMode::Additive, "Keep them in the backup");
+                        ui.radio_value(&mut self.config.sync_mode, SyncMode::Mirror, "Remove them from the backup (mirror)");
+                    });
+                    ui.add_enabled(
+                        self.config.sync_mode == SyncMode::Mirror,
+                        egui::Checkbox::new(&mut self.config.quarantine_deleted, "Move removed files to the quarantine folder"),
+                    );
+
+                    ui.add_space(3.0);
+                    ui.label("When the destination can't hold every file:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.config.space_shortage, SpaceShortage::Abort, "Abort the backup");
+                        ui.radio_value(&mut self.config.space_shortage, SpaceShortage::CopyWhatFits, "Copy the files that fit");
+                    });
+
+                    ui.add_space(3.0);
+                    ui.label("Symbolic links:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.config.symlinks, SymlinkPolicy::Skip, "Skip them");
+                        ui.radio_value(&mut self.config.symlinks, SymlinkPolicy::Follow, "Back up what they point to");
+                        ui.radio_value(&mut self.config.symlinks, SymlinkPolicy::CopyAsLink, "Store them as links");
+                    });
+
+                    ui.add_space(3.0);
+                    ui.add_enabled_ui(self.config.output_format == OutputFormat::Directory, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Only rewrite the changed blocks of files larger than (MB, 0 = off, btrfs or XFS destination):");
+                            size_editor(ui, &mut self.config.delta_min_size);
+                        });
+                    });
+
+                    ui.add_space(3.0);
+                    ui.horizontal(|ui| {
+                        ui.label("Files copied in parallel (0 = one per CPU):");
+                        ui.add(egui::DragValue::new(&mut self.config.worker_threads).clamp_range(0..=64));
+                    });
+
+                    ui.add_space(3.0);
+                    ui.label("Old snapshots to keep after each backup (0 = rule disabled, all 0 = keep everything):");
+                    let retention = &mut self.config.retention;
+                    ui.horizontal(|ui| {
+                        ui.label("Last:");
+                        ui.add(egui::DragValue::new(&mut retention.keep_last));
+                        ui.label("Daily:");
+                        ui.add(egui::DragValue::new(&mut retention.keep_daily));
+                        ui.label("Weekly:");
+                        ui.add(egui::DragValue::new(&mut retention.keep_weekly));
+                        ui.label("Monthly:");
+                        ui.add(egui::DragValue::new(&mut retention.keep_monthly));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Every snapshot younger than (days):");
+                        ui.add(egui::DragValue::new(&mut retention.keep_within_days));
+                    });
+
                     ui.add_space(3.0);
                     let extensions_str = self.config.excluded_extensions.join("\n");
 
@@ -170,12 +468,90 @@ impl eframe::App for BackupConfigGUI {
                     if input_directories != directories_str {
                         self.config.excluded_directories = input_directories.split('\n').map(|s| s.trim().to_string()).collect();
                     }
+
+                    patterns_editor(ui, "Enter glob patterns to exclude (e.g. **/node_modules, *.tmp, build/**/*.o), \"!\" to re-include, the last matching line wins:", &mut self.config.exclude_patterns, 5);
+
+                    patterns_editor(ui, "Enter glob patterns of the only files to back up (empty to back up every file):", &mut self.config.include_patterns, 3);
+
+                    ui.checkbox(&mut self.config.use_ignore_files, "Skip what the .gitignore and .backupignore files of each folder ignore");
+
+                    ui.add_space(3.0);
+                    ui.horizontal(|ui| {
+                        ui.label("Skip files smaller than (MB, 0 for no limit):");
+                        size_editor(ui, &mut self.config.min_file_size);
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Skip files larger than (MB, 0 for no limit):");
+                        size_editor(ui, &mut self.config.max_file_size);
+                    });
+                    date_editor(ui, "Skip files modified before (YYYY-MM-DD, empty for no limit):", &mut self.config.modified_after);
+                    date_editor(ui, "Skip files modified on or after (YYYY-MM-DD, empty for no limit):", &mut self.config.modified_before);
+
+                    let priority_paths_str = self.config.priority_paths.join("\n");
+
+                    ui.label("Paths of the source to back up first, most important first (on different lines):");
+                    ui.add_space(3.0);
+                    let mut input_priority_paths = priority_paths_str.clone();
+                    ui.add(egui::TextEdit::multiline(&mut input_priority_paths).hint_text("Enter paths on different lines").desired_rows(3));
+
+                    if input_priority_paths != priority_paths_str {
+                        self.config.priority_paths = input_priority_paths.split('\n').map(|s| s.trim().to_string()).collect();
+                    }
+
+                    let priority_extensions_str = self.config.priority_extensions.join("\n");
+
+                    ui.label("File extensions to back up next, most important first (on different lines):");
+                    ui.add_space(3.0);
+                    let mut input_priority_extensions = priority_extensions_str.clone();
+                    ui.add(egui::TextEdit::multiline(&mut input_priority_extensions).hint_text("Enter extensions on different lines").desired_rows(3));
+
+                    if input_priority_extensions != priority_extensions_str {
+                        self.config.priority_extensions = input_priority_extensions.split('\n').map(|s| s.trim().to_string()).collect();
+                    }
+
+                    ui.label("Within each group, back up first:");
+                    ui.horizontal(|ui| {
+                        ui.radio_value(&mut self.config.priority_order, FileOrder::Walk, "Any file");
+                        ui.radio_value(&mut self.config.priority_order, FileOrder::NewestFirst, "Most recently modified");
+                        ui.radio_value(&mut self.config.priority_order, FileOrder::SmallestFirst, "Smallest");
+                    });
+                    ui.add_space(10.0);
+                    ui.horizontal(|ui| {
+                        if ui.add_enabled(self.pending_plan.is_none(), egui::Button::new("Preview backup")).clicked() {
+                            self.start_plan();
+                        }
+                        if self.pending_plan.is_some() {
+                            ui.spinner();
+                            ui.label("Looking at the sources...");
+                        }
+                    });
+                    match &self.plan {
+                        Some(Ok(plan)) => {
+                            for action in [PlanAction::New, PlanAction::Changed, PlanAction::Unchanged, PlanAction::Excluded, PlanAction::Deleted] {
+                                let (count, bytes) = plan.total(action);
+                                ui.label(format!("{:?}: {} files, {} bytes", action, count, bytes));
+                            }
+                            egui::CollapsingHeader::new("Files").show(ui, |ui| {
+                                egui::ScrollArea::vertical().id_source("plan-files").max_height(150.0).show(ui, |ui| {
+                                    for entry in &plan.entries {
+                                        let ignored_by = entry.ignored_by.as_ref().map(|f| format!(" ignored by {}", f.display())).unwrap_or_default();
+                                        ui.label(format!("{:?} - {} ({} bytes){}", entry.action, entry.path.display(), entry.size, ignored_by));
+                                    }
+                                });
+                            });
+                        }
+                        Some(Err(e)) => {
+                            ui.label(format!("Failed to compute the preview: {}", e));
+                        }
+                        None => {}
+                    }
+
                     ui.add_space(10.0);
                     ui.horizontal(|ui| {
                         let space = egui::vec2(ui.available_width() * 0.35, 0.0);
                         ui.allocate_space(space);
                         if ui.button("Save options").clicked() {
-                            match self.config.save_info() {
+                            match self.save_profiles() {
                                 Ok(_) => {
                                     self.set_save_message("Info saved successfully".to_string())
                                 }
@@ -196,6 +572,50 @@ impl eframe::App for BackupConfigGUI {
     }
 }
 
+// Multiline editor of glob patterns, flagging the lines that can't be parsed
+fn patterns_editor(ui: &mut egui::Ui, label: &str, patterns: &mut Vec<String>, rows: usize) {
+    let patterns_str = patterns.join("\n");
+    ui.label(label);
+    ui.add_space(3.0);
+    let mut input_patterns = patterns_str.clone();
+    ui.add(egui::TextEdit::multiline(&mut input_patterns).hint_text("Enter patterns on different lines").desired_rows(rows));
+    if input_patterns != patterns_str {
+        *patterns = input_patterns.split('\n').map(|s| s.trim().to_string()).collect();
+    }
+    for error in invalid_patterns(patterns) {
+        ui.colored_label(egui::Color32::RED, format!("Invalid pattern {}", error));
+    }
+}
+
+// Size in bytes edited in MB
+fn size_editor(ui: &mut egui::Ui, size: &mut u64) {
+    const MB: f64 = 1024.0 * 1024.0;
+    let mut megabytes = *size as f64 / MB;
+    if ui.add(egui::DragValue::new(&mut megabytes).speed(1.0).clamp_range(0.0..=f64::MAX)).changed() {
+        *size = (megabytes * MB) as u64;
+    }
+}
+
+fn date_editor(ui: &mut egui::Ui, label: &str, date: &mut String) {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        ui.add(egui::TextEdit::singleline(date).hint_text("YYYY-MM-DD").desired_width(90.0));
+    });
+    if let Err(e) = parse_date(date) {
+        ui.colored_label(egui::Color32::RED, e);
+    }
+}
+
+fn gesture_label(corner: Option<ScreenCorner>) -> &'static str {
+    match corner {
+        None => "No gesture (top left if default profile)",
+        Some(ScreenCorner::TopLeft) => "Top left corner",
+        Some(ScreenCorner::TopRight) => "Top right corner",
+        Some(ScreenCorner::BottomLeft) => "Bottom left corner",
+        Some(ScreenCorner::BottomRight) => "Bottom right corner",
+    }
+}
+
 fn load_gif_frames(path: &str) -> Result<Vec<ColorImage>, Box<dyn Error>> {
     let file = File::open(get_project_path(path))?;
     let reader = BufReader::new(file);
@@ -8,18 +8,20 @@ use crate::utils::load_icon;
 const APP_NAME: &str = "Emergency Backup";
 struct ConfirmGui {
     choice: Sender<Choice>,
-    controller: Arc<Mutex<bool>>
+    controller: Arc<Mutex<bool>>,
+    profiles: Vec<String>,
+    selected: String,
 }
 
 pub enum Choice {
-    Yes,
+    Yes(String), // name of the profile to back up
     No,
     CloseGui, //used to close the gui when user use gesture instead
 }
 
 impl ConfirmGui {
-    pub fn new(choice: Sender<Choice>,controller: Arc<Mutex<bool>>) -> Self {
-        Self { choice, controller }
+    pub fn new(choice: Sender<Choice>,controller: Arc<Mutex<bool>>, profiles: Vec<String>, selected: String) -> Self {
+        Self { choice, controller, profiles, selected }
     }
 }
 
@@ -38,11 +40,19 @@ impl eframe::App for ConfirmGui {
             ui.vertical_centered(|ui| {
                 ui.heading("Do you want to start the  backup?");
                 ui.add_space(6.0);
+                if self.profiles.len() > 1 {
+                    egui::ComboBox::from_label("Profile").selected_text(&self.selected).show_ui(ui, |ui| {
+                        for profile in &self.profiles {
+                            ui.selectable_value(&mut self.selected, profile.clone(), profile);
+                        }
+                    });
+                    ui.add_space(6.0);
+                }
                 ui.horizontal(|ui| {
                     ui.centered_and_justified(|ui| {
                         if ui.button("Yes").clicked() {
                             _frame.close();
-                            self.choice.send(Choice::Yes).expect("Backub must be started");
+                            self.choice.send(Choice::Yes(self.selected.clone())).expect("Backub must be started");
                         }
                         ui.add_space(4.0);
                         if ui.button("No").clicked() {
@@ -56,10 +66,12 @@ impl eframe::App for ConfirmGui {
     }
 }
 
-pub fn run_confirm_gui(sender: Sender<Choice>,controller: Arc<Mutex<bool>>) -> Result<(), Box<dyn Error>>{
+// The profile started by the gesture is preselected, the others can be chosen instead
+pub fn run_confirm_gui(sender: Sender<Choice>,controller: Arc<Mutex<bool>>, profiles: Vec<String>, selected: String) -> Result<(), Box<dyn Error>>{
     let icon = load_icon("assets/backup-file.png")?;
+    let height = if profiles.len() > 1 { 175.0 } else { 140.0 };
     let options = NativeOptions {
-        initial_window_size: Some(egui::vec2(250.0, 140.0)),
+        initial_window_size: Some(egui::vec2(250.0, height)),
         drag_and_drop_support: false,
         resizable: false,
         icon_data: Some(icon),
@@ -70,7 +82,7 @@ pub fn run_confirm_gui(sender: Sender<Choice>,controller: Arc<Mutex<bool>>) -> R
     eframe::run_native(
         APP_NAME,
         options,
-        Box::new(move |_cc| Box::new(ConfirmGui::new(sender,controller))),
+        Box::new(move |_cc| Box::new(ConfirmGui::new(sender,controller, profiles, selected))),
     );
     Ok(())
 }
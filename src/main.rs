@@ -6,17 +6,26 @@ mod config_gui;
 mod confirm_gui;
 mod error_gui;
 mod launcher;
+mod progress_gui;
 
 #[cfg(target_os = "macos")]
 use daemonize::Daemonize;
 
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 use std::{env, thread};
 
-use config::CONFIG_FILE_PATH;
+use backup::progress::ProgressEvent;
+use backup::restore::RestoreOptions;
+use backup::Backupper;
+
+use config::{BackupProfiles, ScreenCorner, CONFIG_FILE_PATH};
 use config_gui::run_config_gui;
 use confirm_gui::{run_confirm_gui, Choice};
 use error_gui::run_error_gui;
+use progress_gui::run_progress_gui;
 use mouse::Mouse;
 use utils::{abort_backup, get_screensize, perform_backup, get_abs_path};
 use winit::event_loop;
@@ -32,6 +41,12 @@ fn main() {
     } else if args.len() == 2 && args[1] == "--screensize" {
         let (width, height) = main_get_screensize();
         println!("{}-{}", width, height);
+    } else if (args.len() == 2 || args.len() == 3) && args[1] == "--dry-run" {
+        main_dry_run(args.get(2).map(String::as_str));
+    } else if (args.len() == 2 || args.len() == 3) && args[1] == "--backup" {
+        main_backup(args.get(2).map(String::as_str));
+    } else if args.len() >= 2 && args[1] == "--restore" {
+        main_restore(&args[2..]);
     } else {
         main_background();
     }
@@ -49,11 +64,15 @@ fn main_background() {
     utils::start_monitor();
     let mut mouse = Mouse::new();
     let screensize = get_screensize();
+    let (width, height) = (screensize.0 as i32, screensize.1 as i32);
     loop {
         let pos = mouse.get_position().unwrap();
-        if pos.x == 0 && pos.y == 0 {
-            if mouse.rectangle_write((screensize.0 as i32) - 1, (screensize.1 as i32) - 1).unwrap() {
-                gesture_identified();
+        let corner = ScreenCorner::ALL.into_iter().find(|c| c.position(width, height) == (pos.x, pos.y));
+        // Profiles are loaded again at each gesture, so that a new configuration is used without restarting
+        let profile = corner.and_then(|c| BackupProfiles::load().for_gesture(c).map(|p| (c, p.name.clone())));
+        if let Some((corner, profile)) = profile {
+            if mouse.rectangle_write(width - 1, height - 1, corner).unwrap() {
+                gesture_identified(profile);
             }
         } else {
             thread::sleep(std::time::Duration::from_secs(1));
@@ -61,16 +80,23 @@ fn main_background() {
     }
 }
 
-fn gesture_identified() {
+fn gesture_identified(profile: String) {
     let mut mouse = Mouse::new();
     let controller = Arc::new(Mutex::new(false));
     let cont_gesture = Arc::clone(&controller);
+    let (progress, progress_receiver) = std::sync::mpsc::channel();
+    let progress_gesture = progress.clone();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel_gesture = Arc::clone(&cancel);
+    let profile_gesture = profile.clone();
     thread::spawn(move || {
         let controller = Arc::clone(&cont_gesture);
         thread::sleep(std::time::Duration::from_secs(1));
         if mouse.confirm(cont_gesture).unwrap() {
             println!("Backup started from gesture");
-            perform_backup(controller).expect("Failed to perform backup");
+            if let Err(e) = perform_backup(&profile_gesture, controller, progress_gesture, cancel_gesture) {
+                println!("Backup not completed: {:?}", e);
+            }
         } else {
             println!("Backup aborted from gesture");
             abort_backup(controller);
@@ -78,10 +104,13 @@ fn gesture_identified() {
     });
 
     // Start GUI confirmation
-    gui_confirmation(controller);
+    gui_confirmation(profile, controller, progress, Arc::clone(&cancel));
+
+    // Whichever way the backup was confirmed, follow it in the progress window
+    run_progress_gui(progress_receiver, cancel).expect("Failed to run progress gui");
 }
 
-fn gui_confirmation(controller: Arc<Mutex<bool>>) {
+fn gui_confirmation(profile: String, controller: Arc<Mutex<bool>>, progress: Sender<ProgressEvent>, cancel: Arc<AtomicBool>) {
     let (sender, receiver) = std::sync::mpsc::channel();
     let controller2 = Arc::clone(&controller);
 
@@ -89,9 +118,11 @@ fn gui_confirmation(controller: Arc<Mutex<bool>>) {
         match receiver.recv() {
             Ok(choice) => {
                 match choice {
-                    Choice::Yes => {
+                    Choice::Yes(profile) => {
                         println!("Backup started from GUI");
-                        perform_backup(controller).expect("Failed to perform backup");
+                        if let Err(e) = perform_backup(&profile, controller, progress, cancel) {
+                            println!("Backup not completed: {:?}", e);
+                        }
                     }
                     Choice::No => {
                         println!("Backup aborted from GUI");
@@ -115,7 +146,8 @@ fn gui_confirmation(controller: Arc<Mutex<bool>>) {
         }
     });
 
-    run_confirm_gui(sender, controller2).expect("Failed to run confirm gui");
+    let profiles = BackupProfiles::load().profiles.into_iter().map(|p| p.name).collect();
+    run_confirm_gui(sender, controller2, profiles, profile).expect("Failed to run confirm gui");
 }
 
 fn main_configuration() {
@@ -129,6 +161,76 @@ fn main_configuration() {
     run_config_gui().unwrap();
 }
 
+fn main_dry_run(profile: Option<&str>) {
+    match Backupper::for_profile(profile).and_then(|backupper| backupper.plan()) {
+        Ok(plan) => print!("{}", plan),
+        Err(e) => eprintln!("Failed to compute backup plan: {:?}", e),
+    }
+}
+
+fn main_backup(profile: Option<&str>) {
+    match Backupper::for_profile(profile).and_then(|mut backupper| backupper.perform_backup_with_stats()) {
+        Ok(_) => println!("Backup completed"),
+        Err(e) => eprintln!("Backup not completed: {:?}", e),
+    }
+}
+
+const RESTORE_USAGE: &str = "Usage: --restore <backup destination or snapshot> <target folder> [--prefix <path>] [--force] [--key-file <path>] [--profile <name>]";
+
+fn restore_usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", RESTORE_USAGE);
+    std::process::exit(2);
+}
+
+// Value of a restore option, which can't be another option
+fn restore_option_value<'a>(rest: &mut impl Iterator<Item = &'a String>, option: &str) -> &'a str {
+    match rest.next() {
+        Some(value) if !value.starts_with("--") => value,
+        _ => restore_usage_error(&format!("Missing value for restore option {}", option)),
+    }
+}
+
+fn main_restore(args: &[String]) {
+    if args.len() < 2 || args[..2].iter().any(|arg| arg.starts_with("--")) {
+        restore_usage_error("Missing backup or target folder");
+    }
+    let mut options = RestoreOptions {
+        backup: PathBuf::from(&args[0]),
+        target: PathBuf::from(&args[1]),
+        prefix: None,
+        force: false,
+        key_file: None,
+    };
+    let mut profile = None;
+    let mut rest = args[2..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--force" => options.force = true,
+            "--prefix" => options.prefix = Some(PathBuf::from(restore_option_value(&mut rest, arg))),
+            "--key-file" => options.key_file = Some(PathBuf::from(restore_option_value(&mut rest, arg))),
+            "--profile" => profile = Some(restore_option_value(&mut rest, arg)),
+            _ => restore_usage_error(&format!("Unknown restore option: {}", arg)),
+        }
+    }
+
+    match Backupper::for_profile(profile).and_then(|backupper| backupper.restore(&options)) {
+        Ok(report) => {
+            println!("Restore completed: {} files restored", report.restored);
+            if !report.skipped_newer.is_empty() {
+                println!(
+                    "{} files were not restored because they are newer than the backup (use --force to overwrite them):",
+                    report.skipped_newer.len()
+                );
+                for file in report.skipped_newer {
+                    println!("  {}", file.display());
+                }
+            }
+        }
+        Err(e) => eprintln!("Failed to restore backup: {:?}", e),
+    }
+}
+
 fn main_get_screensize() -> (u32, u32) {
     let event_loop = event_loop::EventLoop::new();
     let primary_monitor = event_loop.primary_monitor().unwrap();
@@ -1,4 +1,6 @@
-use crate::{sys, types::Confirm, types::Point, types::Rectangle};
+use crate::config::ScreenCorner;
+use crate::{sys, types::Abort, types::Confirm, types::Point, types::Rectangle};
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use crate::utils::play_sound;
 
@@ -15,14 +17,15 @@ impl Mouse {
         self.0.get_position()
     }
 
-    // Wrapper to verify the rectangle is drawn, then we can start the backup
+    // Wrapper to verify the rectangle is drawn from the given corner, then we can start the backup
     pub fn rectangle_write(
         &mut self,
         width: i32,
         height: i32,
+        corner: ScreenCorner,
     ) -> Result<bool, Box<dyn std::error::Error>> {
         let data = Arc::new(self);
-        let mut rect = Rectangle::new(Arc::clone(&data), width, height);
+        let mut rect = Rectangle::new(Arc::clone(&data), width, height, corner.direction());
         play_sound("assets/start_command.mp3");
         let res = rect.draw_rectangle();
         if res {
@@ -38,4 +41,16 @@ impl Mouse {
         let res = conf.confirm(controller);
         Ok(res)
     }
+
+    // Watch for the abort gesture on a screen of the given size until the backup stops running
+    pub fn abort(
+        &mut self,
+        running: Arc<AtomicBool>,
+        width: i32,
+        height: i32,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut abort = Abort::new(self, width, height);
+        let res = abort.abort(running);
+        Ok(res)
+    }
 }
@@ -0,0 +1,141 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use egui::Context;
+use eframe::NativeOptions;
+use crate::backup::progress::{BackupOutcome, BackupProgress, ProgressEvent};
+use crate::utils::load_icon;
+
+const APP_NAME: &str = "Emergency Backup";
+// How long the final result stays on screen before the window closes by itself
+const CLOSE_DELAY: Duration = Duration::from_secs(3);
+
+struct ProgressGui {
+    events: Receiver<ProgressEvent>,
+    cancel: Arc<AtomicBool>,
+    progress: BackupProgress,
+    // Files found so far, until the sources are walked
+    scanning: Option<usize>,
+    finished: Option<(BackupOutcome, Instant)>,
+}
+
+impl ProgressGui {
+    pub fn new(events: Receiver<ProgressEvent>, cancel: Arc<AtomicBool>, first: ProgressEvent) -> Self {
+        let mut gui = Self {
+            events,
+            cancel,
+            progress: BackupProgress::default(),
+            scanning: None,
+            finished: None,
+        };
+        gui.handle_event(first);
+        gui
+    }
+
+    fn handle_event(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::Scanning { files, folder } => {
+                self.scanning = Some(files);
+                self.progress.current_file = folder;
+            }
+            ProgressEvent::Scanned { files, bytes } => {
+                self.scanning = None;
+                self.progress.current_file.clear();
+                self.progress.files_total = files;
+                self.progress.bytes_total = bytes;
+            }
+            ProgressEvent::Progress(progress) => self.progress = progress,
+            ProgressEvent::Finished(outcome) => self.finished = Some((outcome, Instant::now())),
+        }
+    }
+}
+
+impl eframe::App for ProgressGui {
+    fn update(&mut self, ctx: &Context, _frame: &mut eframe::Frame) {
+        ctx.request_repaint_after(Duration::from_millis(200));
+        loop {
+            match self.events.try_recv() {
+                Ok(event) => self.handle_event(event),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    // The backup thread went away without reporting the end
+                    if self.finished.is_none() {
+                        self.finished = Some((BackupOutcome::Failed, Instant::now()));
+                    }
+                    break;
+                }
+            }
+        }
+        if let Some((_, at)) = self.finished {
+            if at.elapsed() > CLOSE_DELAY {
+                _frame.close();
+            }
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.vertical_centered(|ui| {
+                match self.finished {
+                    Some((BackupOutcome::Completed, _)) => ui.heading("Backup completed"),
+                    Some((BackupOutcome::Failed, _)) => ui.heading("Backup failed"),
+                    Some((BackupOutcome::Cancelled, _)) => ui.heading("Backup cancelled"),
+                    None if self.cancel.load(Ordering::Relaxed) => ui.heading("Cancelling backup..."),
+                    None if self.scanning.is_some() => ui.heading("Looking for files..."),
+                    None => ui.heading("Backup in progress..."),
+                };
+                ui.add_space(6.0);
+                match self.scanning {
+                    Some(files) => {
+                        ui.add(egui::ProgressBar::new(0.0).animate(true));
+                        ui.label(format!("{} files found", files));
+                    }
+                    None => {
+                        ui.add(egui::ProgressBar::new(self.progress.fraction()).show_percentage());
+                        ui.label(format!(
+                            "{} / {} files, {} / {} bytes",
+                            self.progress.files_done,
+                            self.progress.files_total,
+                            self.progress.bytes_done,
+                            self.progress.bytes_total
+                        ));
+                    }
+                }
+                if let Some(eta) = self.progress.eta {
+                    ui.label(format!("Time left: {} s", eta.as_secs()));
+                }
+                ui.add(egui::Label::new(&self.progress.current_file).wrap(true));
+                if self.finished.is_none() {
+                    ui.add_space(4.0);
+                    if ui.button("Cancel").clicked() {
+                        self.cancel.store(true, Ordering::Relaxed);
+                    }
+                }
+            });
+        });
+    }
+}
+
+pub fn run_progress_gui(events: Receiver<ProgressEvent>, cancel: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
+    // Only show the window once a backup actually started
+    let first = match events.recv() {
+        Ok(event) => event,
+        Err(_) => return Ok(()),
+    };
+    let icon = load_icon("assets/backup-file.png")?;
+    let options = NativeOptions {
+        initial_window_size: Some(egui::vec2(320.0, 190.0)),
+        drag_and_drop_support: false,
+        resizable: false,
+        icon_data: Some(icon),
+        always_on_top: true,
+        ..Default::default()
+    };
+
+    eframe::run_native(
+        APP_NAME,
+        options,
+        Box::new(move |_cc| Box::new(ProgressGui::new(events, cancel, first))),
+    );
+    Ok(())
+}
@@ -1,6 +1,8 @@
 use std::{fmt, thread};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
+use crate::config::ScreenCorner;
 use crate::mouse::Mouse;
 
 pub mod keys;
@@ -61,16 +63,19 @@ pub struct Rectangle<'a> {
     mouse: Arc<&'a mut Mouse>,
     width: i32,
     height: i32,
+    // Horizontal and vertical direction of the first two sides, away from the starting corner
+    direction: (i32, i32),
 }
 
 impl<'a> Rectangle<'a> {
-    pub fn new(mouse: Arc<&'a mut Mouse>, width: i32, height: i32) -> Self {
+    pub fn new(mouse: Arc<&'a mut Mouse>, width: i32, height: i32, direction: (i32, i32)) -> Self {
         Rectangle {
             x: mouse.get_position().unwrap().x,
             y: mouse.get_position().unwrap().y,
             mouse,
             width,
-            height
+            height,
+            direction
         }
     }
 
@@ -81,46 +86,47 @@ impl<'a> Rectangle<'a> {
 
     pub fn draw_rectangle(&mut self) -> bool {
         let mut flag = true;
+        let (dx, dy) = self.direction;
         
-        // Left -> Right
-        while self.mouse.get_position().unwrap().x < self.x + self.width && flag==true {
+        // Along the horizontal side, away from the corner (Left -> Right from the top left corner)
+        while dx*self.mouse.get_position().unwrap().x < dx*self.x + self.width && flag==true {
             thread::sleep(std::time::Duration::from_millis(100));
             match self.mouse.get_position().unwrap().y < self.y + TOL && self.mouse.get_position().unwrap().y > self.y - TOL {
                 true => {},
                 false => flag = false 
             }
         }
-        self.set_position(self.x + self.width, self.y);
+        self.set_position(self.x + dx*self.width, self.y);
 
-        // Up -> Down
-        while self.mouse.get_position().unwrap().y < self.y + self.height && flag==true{
+        // Along the vertical side, away from the corner (Up -> Down)
+        while dy*self.mouse.get_position().unwrap().y < dy*self.y + self.height && flag==true{
             thread::sleep(std::time::Duration::from_millis(100));
             match self.mouse.get_position().unwrap().x < self.x + TOL && self.mouse.get_position().unwrap().x > self.x - TOL {
                 true => {},
                 false => flag = false
             }
         }
-        self.set_position(self.x, self.y + self.height);
+        self.set_position(self.x, self.y + dy*self.height);
         
-        // Right -> Left
-        while self.mouse.get_position().unwrap().x > self.x-self.width && flag==true {
+        // Back along the horizontal side (Right -> Left)
+        while dx*self.mouse.get_position().unwrap().x > dx*self.x-self.width && flag==true {
             thread::sleep(std::time::Duration::from_millis(100));
             match self.mouse.get_position().unwrap().y < self.y + TOL && self.mouse.get_position().unwrap().y > self.y - TOL {
                 true => {},
                 false => flag = false
             }
         }
-        self.set_position(self.x-self.width, self.y);
+        self.set_position(self.x - dx*self.width, self.y);
 
-        // Down -> Up
-        while self.mouse.get_position().unwrap().y > self.y-self.height && flag==true {
+        // Back to the corner (Down -> Up)
+        while dy*self.mouse.get_position().unwrap().y > dy*self.y-self.height && flag==true {
             thread::sleep(std::time::Duration::from_millis(100));
             match self.mouse.get_position().unwrap().x < self.x + TOL && self.mouse.get_position().unwrap().x > self.x - TOL {
                 true => {},
                 false => flag = false
             }
         }
-        self.set_position(self.x, self.y-self.height);
+        self.set_position(self.x, self.y - dy*self.height);
 
         flag
     }
@@ -191,3 +197,50 @@ impl<'a> Confirm<'a> {
     }  
 }
 
+
+pub struct Abort<'a> {
+    mouse: &'a mut Mouse,
+    width: i32,
+    height: i32,
+}
+
+impl<'a> Abort<'a> {
+    pub fn new(mouse: &'a mut Mouse, width: i32, height: i32) -> Self {
+        Abort { mouse, width, height }
+    }
+
+    // Wait, while the backup is running, for a stroke from a corner of the screen along its vertical edge
+    // to the other corner and back: unlike a plain move, it can't be drawn by accident
+    pub fn abort(&mut self, running: Arc<AtomicBool>) -> bool {
+        let corners = ScreenCorner::ALL.map(|c| c.position(self.width, self.height));
+        // Corner the stroke started from, and whether it reached the other end of the edge
+        let mut stroke: Option<(Point, bool)> = None;
+
+        while running.load(Ordering::Relaxed) {
+            thread::sleep(std::time::Duration::from_millis(100));
+
+            let pos = self.mouse.get_position().unwrap();
+            match stroke {
+                None => {
+                    if corners.contains(&(pos.x, pos.y)) {
+                        stroke = Some((pos, false));
+                    }
+                }
+                // The stroke has to stay along the edge
+                Some((corner, _)) if pos.x >= corner.x + TOL || pos.x <= corner.x - TOL => stroke = None,
+                Some((corner, false)) => {
+                    let other_end = if corner.y == 0 { self.height - 1 } else { 0 };
+                    if (pos.y - other_end).abs() < TOL {
+                        stroke = Some((corner, true));
+                    }
+                }
+                Some((corner, true)) => {
+                    if (pos.y - corner.y).abs() < TOL {
+                        return true;
+                    }
+                }
+            }
+        }
+        false
+    }
+}
@@ -1,13 +1,17 @@
 use chrono::Local;
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use sysinfo::{Pid, System};
 
+use crate::backup::progress::ProgressEvent;
 use crate::backup::{Backupper, BackupperError};
 use crate::config::CPU_USAGE_LOG_PATH;
+use crate::mouse::Mouse;
 use std::process::Command;
 use std::env;
 use std::error::Error;
@@ -15,6 +19,9 @@ use std::path::PathBuf;
 use rodio::{Decoder, OutputStream, Sink};
 use home;
 
+// Played at every quarter of the backup, so that progress can be followed without looking at the screen
+const PROGRESS_SOUND: &str = "assets/backup_progress.wav";
+
 pub fn start_monitor() {
     // start monitoring
     let sys = Arc::new(Mutex::new(System::new_all()));
@@ -44,18 +51,74 @@ pub fn start_monitor() {
     });
 }
 
-pub fn perform_backup(controller: Arc<Mutex<bool>>) -> Result<(), BackupperError> {    
+// Forward the progress of the backup to the progress window, playing a sound at every quarter when enabled
+fn forward_progress(events: Receiver<ProgressEvent>, window: Sender<ProgressEvent>, sounds: bool) {
+    let mut quarter = 0;
+    for event in events {
+        if let ProgressEvent::Progress(progress) = &event {
+            let reached = (progress.fraction() * 4.0) as u32;
+            if sounds && reached > quarter && reached < 4 {
+                thread::spawn(|| play_sound(PROGRESS_SOUND));
+            }
+            quarter = quarter.max(reached);
+        }
+        let _ = window.send(event);
+    }
+}
+
+// Cancel the backup when the abort gesture is drawn, until running goes back to false
+fn watch_abort_gesture(running: Arc<AtomicBool>, cancel: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut mouse = Mouse::new();
+        let (width, height) = get_screensize();
+        if mouse.abort(running, width as i32, height as i32).unwrap() {
+            println!("Backup cancelled from gesture");
+            cancel.store(true, Ordering::Relaxed);
+        }
+    })
+}
+
+pub fn perform_backup(
+    profile: &str,
+    controller: Arc<Mutex<bool>>,
+    progress: Sender<ProgressEvent>,
+    cancel: Arc<AtomicBool>,
+) -> Result<(), BackupperError> {    
     let mut lock = controller.lock().unwrap();
     if !*lock {
         *lock = true;
 
         play_sound("assets/backup_started.mp3");
 
-        let backupper = Backupper::new();
+        let mut backupper = match Backupper::for_profile(Some(profile)) {
+            Ok(backupper) => backupper,
+            Err(e) => {
+                play_sound("assets/backup_aborted.mp3");
+                println!("Failed to perform backup: {:?}", e);
+                return Err(e);
+            }
+        };
+        println!("Backing up profile {}", profile);
+        let (sender, receiver) = channel();
+        backupper.set_progress_sender(sender);
+        backupper.set_cancel_token(Arc::clone(&cancel));
+        let sounds = backupper.backup_config().progress_sounds;
+        let forwarder = thread::spawn(move || forward_progress(receiver, progress, sounds));
+        let running = Arc::new(AtomicBool::new(true));
+        let watcher = watch_abort_gesture(Arc::clone(&running), cancel);
+
         let backup_result = backupper.perform_backup_with_stats();
+        drop(backupper);
+        running.store(false, Ordering::Relaxed);
+        forwarder.join().expect("Failed to forward backup progress");
+        watcher.join().expect("Failed to watch the abort gesture");
 
         match &backup_result {
             Ok(_) => play_sound("assets/backup_finished.mp3"),
+            Err(BackupperError::Cancelled(snapshot)) => {
+                play_sound("assets/backup_cancelled.mp3");
+                println!("Backup cancelled, partial backup kept in {}", snapshot.path.display());
+            }
             Err(e) => {
                 play_sound("assets/backup_aborted.mp3");
                 match e {
@@ -67,7 +130,11 @@ pub fn perform_backup(controller: Arc<Mutex<bool>>) -> Result<(), BackupperError
                     },
                     BackupperError::WalkerError(e) => {
                         println!("Failed to list files: {:?}", e);
-                    }
+                    },
+                    BackupperError::VerifyError(e) => {
+                        println!("Backup verification failed for files: {:?}", e);
+                    },
+                    BackupperError::Cancelled(_) => {}
                 }
             }
         }